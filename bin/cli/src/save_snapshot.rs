@@ -54,6 +54,7 @@ pub async fn save_snapshot(
             rpc_ws_url: ws_url.clone(),
             serum_programs,
             open_orders_authority: mango_group,
+            ..Default::default()
         },
         oracles_and_vaults.clone(),
         account_update_sender.clone(),
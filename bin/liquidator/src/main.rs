@@ -279,6 +279,8 @@ async fn main() -> anyhow::Result<()> {
             rpc_ws_url: ws_url.clone(),
             serum_programs,
             open_orders_authority: mango_group,
+            group: Some(mango_group),
+            ..Default::default()
         },
         mango_oracles.clone(),
         account_update_sender.clone(),
@@ -405,10 +407,14 @@ async fn main() -> anyhow::Result<()> {
         let mut metric_account_update_queue_len =
             metrics.register_u64("account_update_queue_length".into());
         let mut metric_mango_accounts = metrics.register_u64("mango_accounts".into());
+        let mut metric_stale_account_writes =
+            metrics.register_u64("chain_data_stale_account_writes".into());
 
         let mut mint_infos = HashMap::<TokenIndex, Pubkey>::new();
         let mut oracles = HashSet::<Pubkey>::new();
         let mut perp_markets = HashMap::<PerpMarketIndex, Pubkey>::new();
+        // so that accounts on forks of dead slots get their pre-fork versions back
+        let mut fork_journal = account_update_stream::ForkJournal::default();
 
         async move {
             loop {
@@ -418,7 +424,12 @@ async fn main() -> anyhow::Result<()> {
                     .expect("channel not closed");
                 metric_account_update_queue_len.set(account_update_receiver.len() as u64);
 
-                message.update_chain_data(&mut chain_data.write().unwrap());
+                let stale_writes = message.update_chain_data_with_journal(
+                    &mut chain_data.write().unwrap(),
+                    &account_update_stream::ChainDataHooks::default(),
+                    &mut fork_journal,
+                );
+                metric_stale_account_writes.add(stale_writes as u64);
 
                 match message {
                     Message::Account(account_write) => {
@@ -158,6 +158,7 @@ async fn main() -> anyhow::Result<()> {
             rpc_ws_url: ws_url.clone(),
             serum_programs,
             open_orders_authority: mango_group,
+            ..Default::default()
         },
         mango_oracles.clone(),
         account_update_sender.clone(),
@@ -1,7 +1,17 @@
+use solana_account_decoder::{UiAccount, UiDataSliceConfig};
 use solana_client::rpc_response::{Response, RpcKeyedAccount};
-use solana_sdk::{account::AccountSharedData, pubkey::Pubkey};
+use solana_sdk::{
+    account::{AccountSharedData, ReadableAccount},
+    pubkey::Pubkey,
+    signature::Signature,
+    transaction::TransactionError,
+};
 
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str::FromStr,
+    sync::Arc,
+};
 use tracing::*;
 
 use crate::chain_data;
@@ -11,10 +21,18 @@ pub struct AccountUpdate {
     pub pubkey: Pubkey,
     pub slot: u64,
     pub account: AccountSharedData,
+    /// the account exactly as the rpc node sent it, only if requested
+    pub raw: Option<Arc<UiAccount>>,
+    /// the part of the account data in `account` if the subscription requested a
+    /// data slice, None if it is all of it
+    pub data_slice: Option<UiDataSliceConfig>,
 }
 
 impl AccountUpdate {
-    pub fn from_rpc(rpc: Response<RpcKeyedAccount>) -> anyhow::Result<Self> {
+    /// Decodes the rpc account, keeping the original encoded form if `keep_raw`
+    ///
+    /// The data is kept whatever its length, like token-2022 accounts with extensions.
+    pub fn from_rpc(rpc: Response<RpcKeyedAccount>, keep_raw: bool) -> anyhow::Result<Self> {
         let pubkey = Pubkey::from_str(&rpc.value.pubkey)?;
         let account = rpc
             .value
@@ -25,24 +43,182 @@ impl AccountUpdate {
             pubkey,
             slot: rpc.context.slot,
             account,
+            raw: keep_raw.then(|| Arc::new(rpc.value.account)),
+            data_slice: None,
         })
     }
+
+    pub fn owner(&self) -> &Pubkey {
+        self.account.owner()
+    }
+
+    /// Whether the account is owned by `program`, usually mango_v4::id()
+    pub fn is_mango(&self, program: &Pubkey) -> bool {
+        self.owner() == program
+    }
+}
+
+/// Bring a batch of account updates into the order they should be applied in
+///
+/// Within the batch, updates for the same pubkey are ordered by slot. Updates with
+/// the same slot keep their arrival order, which stands in for the write version.
+/// That way applying the batch front to back arrives at the same state as applying
+/// the unbatched updates.
+///
+/// The relative order of updates for different pubkeys is not guaranteed.
+pub fn sort_account_batch(batch: &mut [AccountUpdate]) {
+    // must be a stable sort to keep the write order within a slot
+    batch.sort_by_key(|update| update.slot);
+}
+
+/// Called with the slot of each Root slot update, see ChainDataHooks
+///
+/// Runs inline while chain data is updated, so it must be cheap and not block.
+/// Spawn or send heavy work like checkpointing to another task.
+pub type RootedSlotCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Called with the last_slot of each Message::Disconnected, see ChainDataHooks
+///
+/// Like RootedSlotCallback it runs inline. Usually it triggers a fresh snapshot.
+pub type DisconnectedCallback = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Callbacks for Message::update_chain_data_with_hooks()
+#[derive(Clone, Default)]
+pub struct ChainDataHooks {
+    /// called after applying a Root slot update
+    pub on_rooted_slot: Option<RootedSlotCallback>,
+    /// called for Message::Disconnected: updates after its last_slot may be missing
+    /// from the chain data until a new snapshot is applied
+    pub on_disconnected: Option<DisconnectedCallback>,
 }
 
 #[derive(Clone)]
 pub enum Message {
     Account(AccountUpdate),
+    /// coalesced account updates, sorted like sort_account_batch(), see
+    /// websocket_source::Config::account_coalesce_interval
+    AccountBatch(Vec<AccountUpdate>),
     Snapshot(Vec<AccountUpdate>),
     Slot(Arc<solana_client::rpc_response::SlotUpdate>),
+    /// from slotSubscribe, an alternative to the more detailed Slot messages
+    SlotInfo(Arc<solana_client::rpc_response::SlotInfo>),
+    /// in dual source mode: the rpc nodes sent different data for the account at this slot
+    Disagreement {
+        pubkey: Pubkey,
+        slot: u64,
+    },
+    /// a transaction watched with SourceHandle::watch_signature() was processed
+    Signature {
+        signature: Signature,
+        slot: u64,
+        /// None if the transaction succeeded
+        err: Option<TransactionError>,
+    },
+    /// a connection of the source was lost and reconnects, updates may have been missed
+    ///
+    /// Sent once per lost connection, before the messages of its next connection.
+    /// A source can have several connections, each reports its own disconnects: with
+    /// sharded oracles, see ConnectionRole, and in websocket dual source mode, where
+    /// `url` tells the two sources apart. `last_slot` is the highest slot that
+    /// connection saw so far. Consumers that need complete data should resync, for
+    /// example from a getProgramAccounts snapshot.
+    Disconnected {
+        last_slot: u64,
+        /// the endpoint of the connection
+        url: String,
+        role: ConnectionRole,
+    },
+}
+
+/// What the connection behind a Message::Disconnected subscribed to
+///
+/// See websocket_source::Config::max_subscriptions_per_connection, other sources
+/// only have Main connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionRole {
+    /// everything, with the first shard of the oracles
+    Main,
+    /// only oracles; the slot subscription is kept for liveness but not forwarded
+    OracleShard,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Account,
+    Snapshot,
+    /// Slot and SlotInfo messages
+    Slot,
+    Disagreement,
+    Signature,
+    Disconnected,
 }
 
 impl Message {
-    pub fn update_chain_data(&self, chain: &mut chain_data::ChainData) {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::Account(_) | Message::AccountBatch(_) => MessageKind::Account,
+            Message::Snapshot(_) => MessageKind::Snapshot,
+            Message::Slot(_) | Message::SlotInfo(_) => MessageKind::Slot,
+            Message::Disagreement { .. } => MessageKind::Disagreement,
+            Message::Signature { .. } => MessageKind::Signature,
+            Message::Disconnected { .. } => MessageKind::Disconnected,
+        }
+    }
+
+    /// Apply the message to `chain`, returning the number of stale account writes dropped
+    ///
+    /// An account write is stale if `chain` already has a newer write for the account,
+    /// see apply_account_write(). Summing the returned counts gives a useful metric
+    /// for how much reordering the source does.
+    ///
+    /// Writes at dead slots stay live until a bank on another fork becomes the
+    /// processed head, update_chain_data_with_journal() reverts them right away.
+    pub fn update_chain_data(&self, chain: &mut chain_data::ChainData) -> usize {
+        self.update_chain_data_with_hooks(chain, &ChainDataHooks::default())
+    }
+
+    /// Like update_chain_data(), calling the `hooks` for rooted slots and disconnects
+    ///
+    /// The sources don't call the hooks, they run where the messages are applied.
+    /// Only SlotsUpdates subscriptions produce Root updates, the root field of
+    /// SlotInfo messages does not trigger on_rooted_slot.
+    pub fn update_chain_data_with_hooks(
+        &self,
+        chain: &mut chain_data::ChainData,
+        hooks: &ChainDataHooks,
+    ) -> usize {
+        self.apply_to_chain_data(chain, hooks, None)
+    }
+
+    /// Like update_chain_data_with_hooks(), reverting the writes of dead slots
+    ///
+    /// `journal` remembers what the writes of unrooted slots replaced. When a slot
+    /// dies, the versions from before the dead fork are applied again for the
+    /// accounts written in it or in slots built on it. Pass the same journal for
+    /// every message applied to `chain`.
+    pub fn update_chain_data_with_journal(
+        &self,
+        chain: &mut chain_data::ChainData,
+        hooks: &ChainDataHooks,
+        journal: &mut ForkJournal,
+    ) -> usize {
+        self.apply_to_chain_data(chain, hooks, Some(journal))
+    }
+
+    fn apply_to_chain_data(
+        &self,
+        chain: &mut chain_data::ChainData,
+        hooks: &ChainDataHooks,
+        mut journal: Option<&mut ForkJournal>,
+    ) -> usize {
         use chain_data::*;
+        let mut stale_writes = 0;
         match self {
             Message::Account(account_write) => {
                 trace!("websocket account message");
-                chain.update_account(
+                let outcome = journaled_account_write(
+                    chain,
+                    journal.as_deref_mut(),
                     account_write.pubkey,
                     AccountData {
                         slot: account_write.slot,
@@ -50,10 +226,29 @@ impl Message {
                         write_version: 1,
                     },
                 );
+                stale_writes += (outcome == WriteOutcome::Stale) as usize;
+            }
+            Message::AccountBatch(batch) => {
+                trace!("websocket account batch message");
+                for account_write in batch {
+                    let outcome = journaled_account_write(
+                        chain,
+                        journal.as_deref_mut(),
+                        account_write.pubkey,
+                        AccountData {
+                            slot: account_write.slot,
+                            account: account_write.account.clone(),
+                            write_version: 1,
+                        },
+                    );
+                    stale_writes += (outcome == WriteOutcome::Stale) as usize;
+                }
             }
             Message::Snapshot(snapshot) => {
                 for account_update in snapshot {
-                    chain.update_account(
+                    let outcome = journaled_account_write(
+                        chain,
+                        journal.as_deref_mut(),
                         account_update.pubkey,
                         chain_data::AccountData {
                             slot: account_update.slot,
@@ -61,6 +256,7 @@ impl Message {
                             write_version: 0,
                         },
                     );
+                    stale_writes += (outcome == WriteOutcome::Stale) as usize;
                 }
             }
             Message::Slot(slot_update) => {
@@ -89,12 +285,714 @@ impl Message {
                         status: SlotStatus::Rooted,
                         chain: 0,
                     }),
+                    solana_client::rpc_response::SlotUpdate::Dead { slot, ref err, .. } => {
+                        // ChainData has no status for dead slots. Their writes stop being
+                        // live as soon as a bank on another fork becomes the processed head,
+                        // and the next root prunes them.
+                        debug!("slot {slot} is dead: {err}");
+                        if let Some(journal) = journal.as_deref_mut() {
+                            let reverted = journal.revert_dead(chain, slot);
+                            debug!("reverted {reverted} account writes on the fork of dead slot {slot}");
+                        }
+                        None
+                    }
                     _ => None,
                 };
                 if let Some(update) = slot_update {
+                    if let Some(journal) = journal.as_deref_mut() {
+                        journal.update_slot(&update);
+                    }
+                    chain.update_slot(update);
+                }
+                if let solana_client::rpc_response::SlotUpdate::Root { slot, .. } = **slot_update {
+                    if let Some(on_rooted_slot) = &hooks.on_rooted_slot {
+                        on_rooted_slot(slot);
+                    }
+                }
+            }
+            Message::SlotInfo(slot_info) => {
+                trace!("websocket slot info message");
+                let updates = [
+                    SlotData {
+                        slot: slot_info.slot,
+                        parent: Some(slot_info.parent),
+                        status: SlotStatus::Processed,
+                        chain: 0,
+                    },
+                    SlotData {
+                        slot: slot_info.root,
+                        parent: None,
+                        status: SlotStatus::Rooted,
+                        chain: 0,
+                    },
+                ];
+                for update in updates {
+                    if let Some(journal) = journal.as_deref_mut() {
+                        journal.update_slot(&update);
+                    }
                     chain.update_slot(update);
                 }
             }
+            Message::Disconnected {
+                last_slot,
+                url,
+                role,
+            } => {
+                // the existing data is still the best there is, but it may be stale
+                warn!(
+                    "account source connection to {url} ({role:?}) disconnected after slot {last_slot}, updates may be missing"
+                );
+                if let Some(on_disconnected) = &hooks.on_disconnected {
+                    on_disconnected(*last_slot);
+                }
+            }
+            Message::Disagreement { .. } | Message::Signature { .. } => {}
+        }
+        stale_writes
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum WriteOutcome {
+    Applied,
+    /// identical to the stored write
+    Duplicate,
+    /// older than the stored write
+    Stale,
+}
+
+/// Apply an account write unless `chain` already has a newer one for the pubkey
+///
+/// Last writer wins by (slot, write_version), so duplicate and reordered deliveries,
+/// like after reconnects or snapshot refreshes, can't roll an account back. Rpc
+/// notifications carry no write version: a write with the same (slot, write_version)
+/// as the stored one is only a duplicate if its data is identical, otherwise it
+/// is a later write in the same slot.
+fn apply_account_write(
+    chain: &mut chain_data::ChainData,
+    pubkey: Pubkey,
+    write: chain_data::AccountData,
+) -> WriteOutcome {
+    if let Ok(existing) = chain.account(&pubkey) {
+        let existing_version = (existing.slot, existing.write_version);
+        let write_version = (write.slot, write.write_version);
+        let outcome = if existing_version > write_version {
+            WriteOutcome::Stale
+        } else if existing_version == write_version && existing.account == write.account {
+            WriteOutcome::Duplicate
+        } else {
+            WriteOutcome::Applied
+        };
+        if outcome != WriteOutcome::Applied {
+            trace!(
+                "ignoring account write for {pubkey} at {write_version:?}, have {existing_version:?}"
+            );
+            return outcome;
+        }
+    }
+    chain.update_account(pubkey, write);
+    WriteOutcome::Applied
+}
+
+/// apply_account_write(), remembering what an applied write replaced in `journal`
+fn journaled_account_write(
+    chain: &mut chain_data::ChainData,
+    journal: Option<&mut ForkJournal>,
+    pubkey: Pubkey,
+    write: chain_data::AccountData,
+) -> WriteOutcome {
+    let journal = match journal {
+        Some(journal) => journal,
+        None => return apply_account_write(chain, pubkey, write),
+    };
+    let slot = write.slot;
+    let previous = chain
+        .account(&pubkey)
+        .ok()
+        .map(|data| chain_data::AccountData {
+            slot: data.slot,
+            write_version: data.write_version,
+            account: data.account.clone(),
+        });
+    let outcome = apply_account_write(chain, pubkey, write);
+    if outcome == WriteOutcome::Applied {
+        journal.record_write(slot, pubkey, previous);
+    }
+    outcome
+}
+
+/// What the account writes of unrooted slots replaced, see
+/// Message::update_chain_data_with_journal()
+///
+/// Everything up to the newest root is forgotten, so it stays as small as the
+/// unrooted part of the chain.
+#[derive(Default)]
+pub struct ForkJournal {
+    /// slot to parent, from processed slot updates
+    parents: BTreeMap<u64, u64>,
+    /// per slot, the accounts first written there with their live version before
+    writes: BTreeMap<u64, Vec<(Pubkey, Option<chain_data::AccountData>)>>,
+    newest_rooted_slot: u64,
+}
+
+impl ForkJournal {
+    fn update_slot(&mut self, update: &chain_data::SlotData) {
+        if let Some(parent) = update.parent {
+            self.parents.insert(update.slot, parent);
+        }
+        let rooted = matches!(update.status, chain_data::SlotStatus::Rooted);
+        if rooted && update.slot > self.newest_rooted_slot {
+            self.newest_rooted_slot = update.slot;
+            self.parents = self.parents.split_off(&(update.slot + 1));
+            self.writes = self.writes.split_off(&(update.slot + 1));
+        }
+    }
+
+    fn record_write(
+        &mut self,
+        slot: u64,
+        pubkey: Pubkey,
+        previous: Option<chain_data::AccountData>,
+    ) {
+        if slot <= self.newest_rooted_slot {
+            return;
+        }
+        let writes = self.writes.entry(slot).or_default();
+        if !writes.iter().any(|(written, _)| *written == pubkey) {
+            writes.push((pubkey, previous));
+        }
+    }
+
+    /// Re-apply the versions that the writes of `dead` and its descendants replaced
+    ///
+    /// The pre-fork versions go back in at their own slot and write version, nothing
+    /// is written at the dead slots. Their writes stay in `chain` and stop being live
+    /// once a bank on another fork is the processed head, so a dead slot that turns
+    /// out to be alive after all keeps its data. Accounts created on the dead fork
+    /// have nothing to restore. Returns the number of reverted writes.
+    fn revert_dead(&mut self, chain: &mut chain_data::ChainData, dead: u64) -> usize {
+        // children have higher slots than their parents, one pass finds all descendants
+        let mut dead_slots = BTreeSet::from([dead]);
+        for (&slot, parent) in self.parents.range(dead + 1..) {
+            if dead_slots.contains(parent) {
+                dead_slots.insert(slot);
+            }
+        }
+
+        let mut restore = HashMap::new();
+        let mut reverted = 0;
+        for slot in dead_slots.iter() {
+            self.parents.remove(slot);
+            for (pubkey, previous) in self.writes.remove(slot).unwrap_or_default() {
+                // the first write on the dead fork replaced a version from outside it
+                match previous {
+                    Some(previous) if !dead_slots.contains(&previous.slot) => {
+                        restore.entry(pubkey).or_insert(previous);
+                    }
+                    _ => {}
+                }
+                reverted += 1;
+            }
+        }
+
+        for (pubkey, previous) in restore {
+            chain.update_account(pubkey, previous);
+        }
+        reverted
+    }
+}
+
+/// Only the messages of the selected kinds
+///
+/// For example, replaying just the slot messages of a recorded stream through
+/// update_chain_data or a SlotTree reproduces the fork state without the account noise.
+pub fn filter_kinds<'a, I>(
+    messages: I,
+    kinds: &'a [MessageKind],
+) -> impl Iterator<Item = Message> + 'a
+where
+    I: IntoIterator<Item = Message>,
+    I::IntoIter: 'a,
+{
+    messages
+        .into_iter()
+        .filter(move |message| kinds.contains(&message.kind()))
+}
+
+/// Something that produces Account and Slot messages
+///
+/// Implemented by the websocket and grpc source configs, SourceConfig and, for tests,
+/// mock_source::MockSource. Code that only consumes the messages can be generic over
+/// it and run against any of them.
+pub trait AccountSource {
+    /// Start the source in a new task, sending its messages to `sender`
+    ///
+    /// The task stops once the receiver is dropped.
+    fn start(
+        self,
+        mango_oracles: Vec<Pubkey>,
+        sender: async_channel::Sender<Message>,
+    ) -> tokio::task::JoinHandle<()>;
+}
+
+/// Where Account and Slot messages come from, the sources are interchangeable
+#[derive(Clone)]
+pub enum SourceConfig {
+    Websocket(crate::websocket_source::Config),
+    Grpc(crate::grpc_source::Config),
+}
+
+impl AccountSource for SourceConfig {
+    /// The websocket SourceHandle is not returned, use websocket_source::start()
+    /// directly for its extra controls.
+    fn start(
+        self,
+        mango_oracles: Vec<Pubkey>,
+        sender: async_channel::Sender<Message>,
+    ) -> tokio::task::JoinHandle<()> {
+        match self {
+            SourceConfig::Websocket(config) => config.start(mango_oracles, sender),
+            SourceConfig::Grpc(config) => config.start(mango_oracles, sender),
+        }
+    }
+}
+
+/// The slot graph as described by slot messages, for debugging fork handling
+#[derive(Default)]
+pub struct SlotTree {
+    /// slot -> (parent, latest status)
+    slots: BTreeMap<u64, (Option<u64>, &'static str)>,
+}
+
+impl SlotTree {
+    pub fn apply(&mut self, message: &Message) {
+        use solana_client::rpc_response::SlotUpdate;
+        match message {
+            Message::Slot(update) => match **update {
+                SlotUpdate::CreatedBank { slot, parent, .. } => {
+                    self.set(slot, Some(parent), "processed")
+                }
+                SlotUpdate::OptimisticConfirmation { slot, .. } => {
+                    self.set(slot, None, "confirmed")
+                }
+                SlotUpdate::Root { slot, .. } => self.set(slot, None, "rooted"),
+                SlotUpdate::Dead { slot, .. } => self.set(slot, None, "dead"),
+                _ => {}
+            },
+            Message::SlotInfo(info) => {
+                self.set(info.slot, Some(info.parent), "processed");
+                self.set(info.root, None, "rooted");
+            }
+            _ => {}
+        }
+    }
+
+    fn set(&mut self, slot: u64, parent: Option<u64>, status: &'static str) {
+        let entry = self.slots.entry(slot).or_insert((None, status));
+        if parent.is_some() {
+            entry.0 = parent;
+        }
+        entry.1 = status;
+    }
+
+    fn fmt_subtree(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        children: &HashMap<u64, Vec<u64>>,
+        slot: u64,
+        depth: usize,
+    ) -> std::fmt::Result {
+        let status = self.slots.get(&slot).map(|s| s.1).unwrap_or("unknown");
+        writeln!(f, "{:indent$}{slot} {status}", "", indent = 2 * depth)?;
+        for &child in children.get(&slot).into_iter().flatten() {
+            // long linear chains would indent forever, only indent at forks
+            let child_depth = if children[&slot].len() > 1 {
+                depth + 1
+            } else {
+                depth
+            };
+            self.fmt_subtree(f, children, child, child_depth)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SlotTree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut children = HashMap::<u64, Vec<u64>>::new();
+        let mut roots = vec![];
+        for (&slot, &(parent, _)) in self.slots.iter() {
+            match parent {
+                Some(parent) if self.slots.contains_key(&parent) => {
+                    children.entry(parent).or_default().push(slot)
+                }
+                _ => roots.push(slot),
+            }
+        }
+        for root in roots {
+            self.fmt_subtree(f, &children, root, 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_source::account_update;
+    use solana_client::rpc_response::SlotUpdate;
+
+    #[test]
+    fn test_sort_account_batch() {
+        let a = Pubkey::new_unique();
+        let b = Pubkey::new_unique();
+        // lamports double as write versions
+        let mut batch = vec![
+            account_update(a, 11, 3),
+            account_update(b, 12, 1),
+            account_update(a, 10, 1),
+            account_update(b, 10, 0),
+            account_update(a, 10, 2),
+            account_update(b, 12, 2),
+        ];
+        sort_account_batch(&mut batch);
+
+        for pubkey in [a, b] {
+            let versions = batch
+                .iter()
+                .filter(|u| u.pubkey == pubkey)
+                .map(|u| (u.slot, u.account.lamports()))
+                .collect::<Vec<_>>();
+            let mut sorted = versions.clone();
+            sorted.sort();
+            assert_eq!(versions, sorted);
+        }
+
+        // last-writer-wins application ends up with the newest write per pubkey
+        let mut state = HashMap::new();
+        for u in batch.iter() {
+            state.insert(u.pubkey, (u.slot, u.account.lamports()));
+        }
+        assert_eq!(state[&a], (11, 3));
+        assert_eq!(state[&b], (12, 2));
+    }
+
+    #[test]
+    fn test_slot_tree() {
+        let slot = |update: SlotUpdate| Message::Slot(Arc::new(update));
+        let messages = vec![
+            slot(SlotUpdate::CreatedBank {
+                slot: 1,
+                parent: 0,
+                timestamp: 0,
+            }),
+            Message::Account(account_update(Pubkey::new_unique(), 1, 0)),
+            slot(SlotUpdate::CreatedBank {
+                slot: 2,
+                parent: 1,
+                timestamp: 0,
+            }),
+            slot(SlotUpdate::CreatedBank {
+                slot: 3,
+                parent: 1,
+                timestamp: 0,
+            }),
+            slot(SlotUpdate::Dead {
+                slot: 2,
+                timestamp: 0,
+                err: String::new(),
+            }),
+            slot(SlotUpdate::Root {
+                slot: 1,
+                timestamp: 0,
+            }),
+        ];
+
+        let mut tree = SlotTree::default();
+        let slot_messages = filter_kinds(messages, &[MessageKind::Slot]).collect::<Vec<_>>();
+        assert_eq!(slot_messages.len(), 5);
+        for message in slot_messages.iter() {
+            tree.apply(message);
+        }
+        assert_eq!(tree.to_string(), "1 rooted\n  2 dead\n  3 processed\n");
+    }
+
+    #[test]
+    fn test_update_chain_data_last_writer_wins() {
+        let pubkey = Pubkey::new_unique();
+        let mut chain = chain_data::ChainData::new();
+        for slot in 10..=12 {
+            chain.update_slot(chain_data::SlotData {
+                slot,
+                parent: Some(slot - 1),
+                status: chain_data::SlotStatus::Rooted,
+                chain: 0,
+            });
+        }
+        let stored = |chain: &chain_data::ChainData| {
+            let data = chain.account(&pubkey).unwrap();
+            (data.slot, data.account.lamports())
+        };
+
+        // out of order: the older write arrives last
+        assert_eq!(
+            Message::Account(account_update(pubkey, 12, 3)).update_chain_data(&mut chain),
+            0
+        );
+        assert_eq!(
+            Message::Account(account_update(pubkey, 10, 1)).update_chain_data(&mut chain),
+            1
+        );
+        assert_eq!(stored(&chain), (12, 3));
+
+        // duplicates change nothing and are not stale
+        assert_eq!(
+            Message::Account(account_update(pubkey, 12, 3)).update_chain_data(&mut chain),
+            0
+        );
+        assert_eq!(stored(&chain), (12, 3));
+
+        // a snapshot of the same slot is older than the websocket write
+        assert_eq!(
+            Message::Snapshot(vec![account_update(pubkey, 12, 2)]).update_chain_data(&mut chain),
+            1
+        );
+        assert_eq!(stored(&chain), (12, 3));
+
+        // a different write in the same slot is a later one
+        Message::Account(account_update(pubkey, 12, 4)).update_chain_data(&mut chain);
+        assert_eq!(stored(&chain), (12, 4));
+
+        // reordered and duplicated deliveries end at the newest write
+        let mut chain_shuffled = chain_data::ChainData::new();
+        for slot in 10..=12 {
+            chain_shuffled.update_slot(chain_data::SlotData {
+                slot,
+                parent: Some(slot - 1),
+                status: chain_data::SlotStatus::Rooted,
+                chain: 0,
+            });
+        }
+        for (slot, lamports) in [(11, 2), (12, 4), (10, 1), (11, 2), (12, 4), (10, 1)] {
+            Message::Account(account_update(pubkey, slot, lamports))
+                .update_chain_data(&mut chain_shuffled);
+        }
+        assert_eq!(stored(&chain_shuffled), (12, 4));
+    }
+
+    #[test]
+    fn test_update_chain_data_drops_stale_writes() {
+        let pubkey = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let mut chain = chain_data::ChainData::new();
+        for slot in 48..=50 {
+            chain.update_slot(chain_data::SlotData {
+                slot,
+                parent: Some(slot - 1),
+                status: chain_data::SlotStatus::Rooted,
+                chain: 0,
+            });
+        }
+
+        // interleaved with writes to another account, the slot 49 write is stale
+        let messages = vec![
+            Message::Account(account_update(other, 48, 7)),
+            Message::Account(account_update(pubkey, 50, 50)),
+            Message::Account(account_update(other, 49, 8)),
+            Message::Account(account_update(pubkey, 49, 49)),
+            Message::Snapshot(vec![
+                account_update(pubkey, 49, 48),
+                account_update(other, 50, 9),
+            ]),
+        ];
+        let stale_writes: usize = messages
+            .iter()
+            .map(|message| message.update_chain_data(&mut chain))
+            .sum();
+        assert_eq!(stale_writes, 2);
+
+        let data = chain.account(&pubkey).unwrap();
+        assert_eq!((data.slot, data.account.lamports()), (50, 50));
+        let data = chain.account(&other).unwrap();
+        assert_eq!((data.slot, data.account.lamports()), (50, 9));
+    }
+
+    #[test]
+    fn test_update_chain_data_dead_slot() {
+        let pubkey = Pubkey::new_unique();
+        let mut chain = chain_data::ChainData::new();
+        let slot = |update: SlotUpdate| Message::Slot(Arc::new(update));
+        let lamports = |chain: &chain_data::ChainData| {
+            let data = chain.account(&pubkey).unwrap();
+            (data.slot, data.account.lamports())
+        };
+
+        slot(SlotUpdate::Root {
+            slot: 10,
+            timestamp: 0,
+        })
+        .update_chain_data(&mut chain);
+        Message::Account(account_update(pubkey, 10, 1)).update_chain_data(&mut chain);
+        slot(SlotUpdate::CreatedBank {
+            slot: 11,
+            parent: 10,
+            timestamp: 0,
+        })
+        .update_chain_data(&mut chain);
+        Message::Account(account_update(pubkey, 11, 2)).update_chain_data(&mut chain);
+        assert_eq!(lamports(&chain), (11, 2));
+
+        // slot 11 dies, the cluster continues on a fork from slot 10
+        slot(SlotUpdate::Dead {
+            slot: 11,
+            timestamp: 0,
+            err: "replay failed".into(),
+        })
+        .update_chain_data(&mut chain);
+        slot(SlotUpdate::CreatedBank {
+            slot: 12,
+            parent: 10,
+            timestamp: 0,
+        })
+        .update_chain_data(&mut chain);
+        assert_eq!(lamports(&chain), (10, 1));
+
+        // rooting the new fork prunes the dead branch
+        slot(SlotUpdate::Root {
+            slot: 12,
+            timestamp: 0,
+        })
+        .update_chain_data(&mut chain);
+        assert_eq!(lamports(&chain), (10, 1));
+        assert_eq!(chain.slots_count(), 1);
+    }
+
+    #[test]
+    fn test_update_chain_data_with_journal_reverts_dead_fork() {
+        let pubkey = Pubkey::new_unique();
+        let created = Pubkey::new_unique();
+        let mut chain = chain_data::ChainData::new();
+        let mut journal = ForkJournal::default();
+        let hooks = ChainDataHooks::default();
+        let mut apply = |message: Message, chain: &mut chain_data::ChainData| {
+            message.update_chain_data_with_journal(chain, &hooks, &mut journal)
+        };
+        let slot = |update: SlotUpdate| Message::Slot(Arc::new(update));
+        let lamports = |chain: &chain_data::ChainData, pubkey| {
+            chain
+                .account(pubkey)
+                .map(|data| data.account.lamports())
+                .unwrap()
+        };
+
+        apply(
+            slot(SlotUpdate::Root {
+                slot: 10,
+                timestamp: 0,
+            }),
+            &mut chain,
+        );
+        apply(Message::Account(account_update(pubkey, 10, 1)), &mut chain);
+        for (slot_number, parent) in [(11, 10), (12, 11)] {
+            apply(
+                slot(SlotUpdate::CreatedBank {
+                    slot: slot_number,
+                    parent,
+                    timestamp: 0,
+                }),
+                &mut chain,
+            );
+            apply(
+                Message::Account(account_update(pubkey, slot_number, slot_number)),
+                &mut chain,
+            );
+        }
+        apply(Message::Account(account_update(created, 12, 5)), &mut chain);
+        assert_eq!(lamports(&chain, &pubkey), 12);
+
+        // no bank on another fork yet, nothing is written at 11 or its child 12
+        apply(
+            slot(SlotUpdate::Dead {
+                slot: 11,
+                timestamp: 0,
+                err: "replay failed".into(),
+            }),
+            &mut chain,
+        );
+        assert_eq!(lamports(&chain, &pubkey), 12);
+
+        // so a later write at the dead slot is not shadowed by the revert
+        assert_eq!(
+            apply(Message::Account(account_update(pubkey, 12, 20)), &mut chain),
+            0
+        );
+        assert_eq!(lamports(&chain, &pubkey), 20);
+
+        // the new fork continues from slot 10
+        apply(
+            slot(SlotUpdate::CreatedBank {
+                slot: 13,
+                parent: 10,
+                timestamp: 0,
+            }),
+            &mut chain,
+        );
+        assert_eq!(lamports(&chain, &pubkey), 1);
+        assert!(chain.account(&created).is_err());
+        apply(Message::Account(account_update(pubkey, 13, 7)), &mut chain);
+        assert_eq!(lamports(&chain, &pubkey), 7);
+        apply(
+            slot(SlotUpdate::Root {
+                slot: 13,
+                timestamp: 0,
+            }),
+            &mut chain,
+        );
+        assert_eq!(lamports(&chain, &pubkey), 7);
+        assert!(journal.writes.is_empty() && journal.parents.is_empty());
+    }
+
+    #[test]
+    fn test_on_rooted_slot() {
+        let rooted = Arc::new(std::sync::Mutex::new(vec![]));
+        let disconnected = Arc::new(std::sync::Mutex::new(vec![]));
+        let hooks = ChainDataHooks {
+            on_rooted_slot: Some({
+                let rooted = rooted.clone();
+                Arc::new(move |slot| rooted.lock().unwrap().push(slot))
+            }),
+            on_disconnected: Some({
+                let disconnected = disconnected.clone();
+                Arc::new(move |slot| disconnected.lock().unwrap().push(slot))
+            }),
+        };
+
+        let mut chain = chain_data::ChainData::new();
+        let messages = vec![
+            Message::Slot(Arc::new(SlotUpdate::CreatedBank {
+                slot: 5,
+                parent: 4,
+                timestamp: 0,
+            })),
+            Message::Slot(Arc::new(SlotUpdate::Root {
+                slot: 4,
+                timestamp: 0,
+            })),
+            Message::Account(account_update(Pubkey::new_unique(), 5, 0)),
+            Message::Slot(Arc::new(SlotUpdate::Root {
+                slot: 5,
+                timestamp: 0,
+            })),
+            Message::Disconnected {
+                last_slot: 6,
+                url: "ws://localhost".into(),
+                role: ConnectionRole::Main,
+            },
+        ];
+        for message in messages.iter() {
+            message.update_chain_data_with_hooks(&mut chain, &hooks);
         }
+        assert_eq!(*rooted.lock().unwrap(), vec![4, 5]);
+        assert_eq!(*disconnected.lock().unwrap(), vec![6]);
     }
 }
@@ -1,2 +1,149 @@
 pub use crate::chain_data_fetcher::AccountFetcher;
 pub use mango_feeds_connector::chain_data::*;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use std::path::Path;
+
+/// Bumped when the file layout written by save_accounts() changes
+const PERSISTED_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PersistedAccount {
+    pubkey: Pubkey,
+    slot: u64,
+    write_version: u64,
+    account: Account,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedChainData {
+    version: u32,
+    accounts: Vec<PersistedAccount>,
+}
+
+/// Write the newest live state of all accounts in `chain` to `path`
+///
+/// Meant for warm restarts, see load_accounts(). Returns the number of accounts.
+pub fn save_accounts(chain: &ChainData, path: &Path) -> anyhow::Result<usize> {
+    let persisted = PersistedChainData {
+        version: PERSISTED_VERSION,
+        accounts: chain
+            .iter_accounts()
+            .map(|(pubkey, data)| PersistedAccount {
+                pubkey: *pubkey,
+                slot: data.slot,
+                write_version: data.write_version,
+                account: data.account.clone().into(),
+            })
+            .collect(),
+    };
+    let bytes = bincode::serialize(&persisted)?;
+
+    // write and rename, to never leave a partially written file behind
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, bytes).with_context(|| format!("writing {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("renaming {} to {}", tmp_path.display(), path.display()))?;
+    Ok(persisted.accounts.len())
+}
+
+/// Prime `chain` with accounts written by save_accounts(), returning their number
+///
+/// The accounts keep their original slots and write versions, so newer updates from
+/// the websocket supersede them. The newest loaded slot is marked as rooted to make
+/// the loaded writes visible before live slot updates arrive, even though some of
+/// them may have been only processed when they were saved.
+pub fn load_accounts(chain: &mut ChainData, path: &Path) -> anyhow::Result<usize> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let persisted: PersistedChainData = bincode::deserialize(&bytes)
+        .with_context(|| format!("decoding chain data from {}", path.display()))?;
+    if persisted.version != PERSISTED_VERSION {
+        anyhow::bail!(
+            "chain data in {} has version {}, expected {PERSISTED_VERSION}",
+            path.display(),
+            persisted.version
+        );
+    }
+
+    let newest_slot = persisted.accounts.iter().map(|a| a.slot).max();
+    for persisted_account in persisted.accounts.iter() {
+        chain.update_account(
+            persisted_account.pubkey,
+            AccountData {
+                slot: persisted_account.slot,
+                write_version: persisted_account.write_version,
+                account: persisted_account.account.clone().into(),
+            },
+        );
+    }
+    if let Some(slot) = newest_slot {
+        chain.update_slot(SlotData {
+            slot,
+            parent: None,
+            status: SlotStatus::Rooted,
+            chain: 0,
+        });
+    }
+    Ok(persisted.accounts.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::account::{AccountSharedData, ReadableAccount};
+
+    #[test]
+    fn test_save_load_accounts() {
+        let mut chain = ChainData::new();
+        chain.update_slot(SlotData {
+            slot: 10,
+            parent: None,
+            status: SlotStatus::Rooted,
+            chain: 0,
+        });
+        let pubkey = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut account = AccountSharedData::new(100, 3, &owner);
+        account.set_data_from_slice(&[1, 2, 3]);
+        chain.update_account(
+            pubkey,
+            AccountData {
+                slot: 10,
+                write_version: 5,
+                account,
+            },
+        );
+
+        let path = std::env::temp_dir().join(format!("chain-data-{}.bin", Pubkey::new_unique()));
+        assert_eq!(save_accounts(&chain, &path).unwrap(), 1);
+
+        let mut loaded = ChainData::new();
+        assert_eq!(load_accounts(&mut loaded, &path).unwrap(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        let data = loaded.account(&pubkey).unwrap();
+        assert_eq!(data.slot, 10);
+        assert_eq!(data.write_version, 5);
+        assert_eq!(data.account.owner(), &owner);
+        assert_eq!(data.account.data(), &[1, 2, 3]);
+
+        // a newer live write supersedes the loaded one
+        loaded.update_slot(SlotData {
+            slot: 11,
+            parent: Some(10),
+            status: SlotStatus::Rooted,
+            chain: 0,
+        });
+        loaded.update_account(
+            pubkey,
+            AccountData {
+                slot: 11,
+                write_version: 1,
+                account: AccountSharedData::new(200, 0, &owner),
+            },
+        );
+        assert_eq!(loaded.account(&pubkey).unwrap().account.lamports(), 200);
+    }
+}
@@ -0,0 +1,189 @@
+//! Time source for timeouts and watchdogs, replaceable in tests
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Resolves once `duration` has passed according to this clock
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The system clock and tokio timers
+#[derive(Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// A clock that only moves when advance() is called
+pub struct MockClock {
+    start: Instant,
+    inner: Mutex<MockClockInner>,
+}
+
+struct MockClockInner {
+    elapsed: Duration,
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            inner: Mutex::new(MockClockInner {
+                elapsed: Duration::ZERO,
+                sleepers: vec![],
+            }),
+        }
+    }
+}
+
+impl MockClock {
+    /// Move time forward, waking all sleeps that are due
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.elapsed += duration;
+        let now = inner.elapsed;
+        let (due, pending) = std::mem::take(&mut inner.sleepers)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        inner.sleepers = pending;
+        for (_, sender) in due {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Number of sleeps that are still waiting
+    pub fn pending_sleeps(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sleepers.retain(|(_, sender)| !sender.is_closed());
+        inner.sleepers.len()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.inner.lock().unwrap().elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        if duration.is_zero() {
+            return futures::future::ready(()).boxed();
+        }
+        let (sender, receiver) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        // forget sleeps whose futures were dropped
+        inner.sleepers.retain(|(_, sender)| !sender.is_closed());
+        let deadline = inner.elapsed + duration;
+        inner.sleepers.push((deadline, sender));
+        async move {
+            // if the clock is dropped, never wake up
+            if receiver.await.is_err() {
+                futures::future::pending::<()>().await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Ticks every `period` according to a Clock, like tokio::time::interval
+///
+/// Missed ticks are skipped instead of being delivered in a burst. tick() is
+/// cancel safe, for use in select! loops.
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    next: Instant,
+    sleep: Option<BoxFuture<'static, ()>>,
+}
+
+impl Interval {
+    /// The first tick is one `period` from now, unlike with tokio::time::interval
+    ///
+    /// Panics if `period` is zero.
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        assert!(!period.is_zero(), "interval period must be non-zero");
+        let next = clock.now() + period;
+        Self {
+            clock,
+            period,
+            next,
+            sleep: None,
+        }
+    }
+
+    pub async fn tick(&mut self) {
+        loop {
+            let now = self.clock.now();
+            if now >= self.next {
+                break;
+            }
+            let remaining = self.next - now;
+            let clock = &self.clock;
+            self.sleep
+                .get_or_insert_with(|| clock.sleep(remaining))
+                .await;
+            self.sleep = None;
+        }
+        self.sleep = None;
+        let behind = self.clock.now() - self.next;
+        let missed = (behind.as_nanos() / self.period.as_nanos()) as u32;
+        self.next += self.period * (missed + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::default();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(10));
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+        assert_eq!(clock.pending_sleeps(), 1);
+
+        clock.advance(Duration::from_secs(8));
+        assert!(long.now_or_never().is_some());
+        assert_eq!(clock.pending_sleeps(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_interval() {
+        let clock = Arc::new(MockClock::default());
+        let mut interval = Interval::new(clock.clone(), Duration::from_secs(10));
+        assert!(interval.tick().now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(interval.tick().now_or_never().is_some());
+        assert!(interval.tick().now_or_never().is_none());
+
+        // two periods passed, one tick and the next is a period later again
+        clock.advance(Duration::from_secs(25));
+        assert!(interval.tick().now_or_never().is_some());
+        assert!(interval.tick().now_or_never().is_none());
+        clock.advance(Duration::from_secs(4));
+        assert!(interval.tick().now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(interval.tick().now_or_never().is_some());
+    }
+}
@@ -0,0 +1,524 @@
+//! Account and slot updates from a Yellowstone geyser gRPC endpoint
+//!
+//! Produces the same Message::Account and Message::Slot messages as websocket_source,
+//! so consumers can switch between the two, see account_update_stream::SourceConfig.
+
+use anyhow::Context;
+use futures::{Future, Stream, StreamExt};
+use solana_client::rpc_response::SlotUpdate;
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    commitment_config::CommitmentConfig,
+    pubkey::Pubkey,
+};
+use tonic::{
+    metadata::AsciiMetadataValue,
+    transport::{ClientTlsConfig, Endpoint},
+};
+use tracing::*;
+use yellowstone_grpc_proto::prelude::{
+    geyser_client::GeyserClient, subscribe_request_filter_accounts_filter::Filter,
+    subscribe_request_filter_accounts_filter_memcmp::Data, subscribe_update::UpdateOneof,
+    CommitmentLevel, SubscribeRequest, SubscribeRequestFilterAccounts,
+    SubscribeRequestFilterAccountsFilter, SubscribeRequestFilterAccountsFilterMemcmp,
+    SubscribeRequestFilterSlots, SubscribeUpdate, SubscribeUpdateAccount, SubscribeUpdateSlot,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use crate::account_update_stream::{AccountSource, AccountUpdate, ConnectionRole, Message};
+use crate::clock::{Clock, RealClock};
+use crate::websocket_source::{reconnect_backoff, Metrics, OpenOrdersFilter};
+
+#[derive(Clone)]
+pub struct Config {
+    /// like http://localhost:10000, https endpoints use tls with the native roots
+    pub grpc_url: String,
+    /// sent as the x-token header, for endpoints that need authentication
+    pub x_token: Option<String>,
+    pub serum_programs: Vec<Pubkey>,
+    pub open_orders_authority: Pubkey,
+    /// how OpenOrders accounts of serum_programs are recognized, serum v3 by default
+    pub open_orders_filter: OpenOrdersFilter,
+    /// commitment of the account updates, slot updates are sent for all statuses
+    pub commitment: CommitmentConfig,
+    pub connect_timeout: Duration,
+    /// wait this long before reconnecting after the stream ended or failed, doubled
+    /// for every further disconnect in a row up to reconnect_backoff_max
+    ///
+    /// Streams that ended cleanly wait half of it, like with websocket_source.
+    pub reconnect_backoff_base: Duration,
+    pub reconnect_backoff_max: Duration,
+    /// a stream that stayed subscribed for this long resets the backoff
+    pub reconnect_backoff_reset_after: Duration,
+    /// only accounts_decoded and decode_errors are counted
+    pub metrics: Arc<Metrics>,
+    /// time source for the reconnect backoff, replaceable for tests
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            grpc_url: String::new(),
+            x_token: None,
+            serum_programs: vec![],
+            open_orders_authority: Pubkey::default(),
+            open_orders_filter: OpenOrdersFilter::default(),
+            commitment: CommitmentConfig::processed(),
+            connect_timeout: Duration::from_secs(10),
+            reconnect_backoff_base: Duration::from_secs(5),
+            reconnect_backoff_max: Duration::from_secs(60),
+            reconnect_backoff_reset_after: Duration::from_secs(30),
+            metrics: Arc::new(Metrics::default()),
+            clock: Arc::new(RealClock),
+        }
+    }
+}
+
+fn commitment_level(commitment: CommitmentConfig) -> CommitmentLevel {
+    if commitment.is_finalized() {
+        CommitmentLevel::Finalized
+    } else if commitment.is_confirmed() {
+        CommitmentLevel::Confirmed
+    } else {
+        CommitmentLevel::Processed
+    }
+}
+
+fn subscribe_request(config: &Config, mango_oracles: &[Pubkey]) -> SubscribeRequest {
+    let mut accounts = HashMap::new();
+    accounts.insert(
+        "mango".to_string(),
+        SubscribeRequestFilterAccounts {
+            owner: vec![mango_v4::id().to_string()],
+            ..Default::default()
+        },
+    );
+    // a filter without accounts would match all accounts
+    if !mango_oracles.is_empty() {
+        accounts.insert(
+            "oracles".to_string(),
+            SubscribeRequestFilterAccounts {
+                account: mango_oracles.iter().map(|pk| pk.to_string()).collect(),
+                ..Default::default()
+            },
+        );
+    }
+    let open_orders_filters = std::iter::once(Filter::Datasize(
+        config.open_orders_filter.expected_data_size(),
+    ))
+    .chain(
+        config
+            .open_orders_filter
+            .memcmps(&config.open_orders_authority)
+            .into_iter()
+            .map(|(offset, bytes)| {
+                Filter::Memcmp(SubscribeRequestFilterAccountsFilterMemcmp {
+                    offset: offset as u64,
+                    data: Some(Data::Bytes(bytes)),
+                })
+            }),
+    )
+    .map(|filter| SubscribeRequestFilterAccountsFilter {
+        filter: Some(filter),
+    })
+    .collect::<Vec<_>>();
+    for (i, serum_program) in config.serum_programs.iter().enumerate() {
+        accounts.insert(
+            format!("open_orders_{i}"),
+            SubscribeRequestFilterAccounts {
+                owner: vec![serum_program.to_string()],
+                filters: open_orders_filters.clone(),
+                ..Default::default()
+            },
+        );
+    }
+
+    let mut slots = HashMap::new();
+    slots.insert("slots".to_string(), SubscribeRequestFilterSlots::default());
+
+    SubscribeRequest {
+        accounts,
+        slots,
+        commitment: Some(commitment_level(config.commitment) as i32),
+        ..Default::default()
+    }
+}
+
+fn account_update(update: SubscribeUpdateAccount) -> anyhow::Result<AccountUpdate> {
+    let info = update.account.context("account update without account")?;
+    let pubkey = Pubkey::try_from(info.pubkey.as_slice())
+        .map_err(|_| anyhow::anyhow!("invalid account pubkey"))?;
+    let owner = Pubkey::try_from(info.owner.as_slice())
+        .map_err(|_| anyhow::anyhow!("invalid owner pubkey for {pubkey}"))?;
+    Ok(AccountUpdate {
+        pubkey,
+        slot: update.slot,
+        account: AccountSharedData::from(Account {
+            lamports: info.lamports,
+            data: info.data,
+            owner,
+            executable: info.executable,
+            rent_epoch: info.rent_epoch,
+        }),
+        raw: None,
+        data_slice: None,
+    })
+}
+
+fn slot_update(update: SubscribeUpdateSlot) -> anyhow::Result<SlotUpdate> {
+    let slot = update.slot;
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    let status = CommitmentLevel::from_i32(update.status)
+        .with_context(|| format!("unknown slot status {} for slot {slot}", update.status))?;
+    Ok(match (status, update.parent) {
+        (CommitmentLevel::Processed, Some(parent)) => SlotUpdate::CreatedBank {
+            slot,
+            parent,
+            timestamp,
+        },
+        (CommitmentLevel::Processed, None) => SlotUpdate::Completed { slot, timestamp },
+        (CommitmentLevel::Confirmed, _) => SlotUpdate::OptimisticConfirmation { slot, timestamp },
+        (CommitmentLevel::Finalized, _) => SlotUpdate::Root { slot, timestamp },
+    })
+}
+
+/// The message for one update and its slot, None for updates that aren't forwarded
+///
+/// An account that can't be decoded is skipped and counted in Metrics::decode_errors,
+/// it doesn't end the stream.
+fn update_message(config: &Config, update: UpdateOneof) -> anyhow::Result<Option<(Message, u64)>> {
+    Ok(match update {
+        UpdateOneof::Account(update) => match account_update(update) {
+            Ok(update) => {
+                config
+                    .metrics
+                    .accounts_decoded
+                    .fetch_add(1, Ordering::Relaxed);
+                let slot = update.slot;
+                Some((Message::Account(update), slot))
+            }
+            Err(err) => {
+                debug!("skipping grpc account update that could not be decoded: {err:?}");
+                config.metrics.record_decode_error();
+                None
+            }
+        },
+        UpdateOneof::Slot(update) => {
+            let slot = update.slot;
+            Some((Message::Slot(slot_update(update)?.into()), slot))
+        }
+        _ => None,
+    })
+}
+
+/// What run() got to, for Message::Disconnected
+#[derive(Default)]
+struct Progress {
+    subscribed: bool,
+    /// highest slot of any forwarded message, on any connection
+    last_slot: u64,
+}
+
+/// Connects and subscribes, returning the update stream
+async fn subscribe(
+    config: Config,
+    mango_oracles: Vec<Pubkey>,
+) -> anyhow::Result<tonic::Streaming<SubscribeUpdate>> {
+    let mut endpoint = Endpoint::from_shared(config.grpc_url.clone())
+        .with_context(|| format!("invalid grpc url {}", config.grpc_url))?
+        .connect_timeout(config.connect_timeout);
+    if config.grpc_url.starts_with("https") {
+        endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+    }
+    let channel = endpoint
+        .connect()
+        .await
+        .with_context(|| format!("connecting to {}", config.grpc_url))?;
+
+    let x_token = config
+        .x_token
+        .as_ref()
+        .map(|token| token.parse::<AsciiMetadataValue>())
+        .transpose()
+        .context("x_token is not a valid header value")?;
+    let mut client =
+        GeyserClient::with_interceptor(channel, move |mut request: tonic::Request<()>| {
+            if let Some(token) = &x_token {
+                request.metadata_mut().insert("x-token", token.clone());
+            }
+            Ok(request)
+        });
+
+    let request = subscribe_request(&config, &mango_oracles);
+    let stream = client
+        .subscribe(futures::stream::once(async move { request }))
+        .await
+        .context("subscribing")?
+        .into_inner();
+    info!("grpc subscriptions to {} are set up", config.grpc_url);
+    Ok(stream)
+}
+
+/// Forwards updates until the stream ends, fails or `sender` closes
+///
+/// Ok if the stream ended without an error.
+async fn feed_data(
+    config: &Config,
+    mut stream: impl Stream<Item = Result<SubscribeUpdate, tonic::Status>> + Unpin,
+    progress: &mut Progress,
+    sender: &async_channel::Sender<Message>,
+) -> anyhow::Result<()> {
+    while let Some(update) = stream.next().await {
+        let update = match update.context("reading from stream")?.update_oneof {
+            Some(update) => update,
+            None => continue,
+        };
+        let (message, slot) = match update_message(config, update)? {
+            Some(message) => message,
+            None => continue,
+        };
+        progress.last_slot = progress.last_slot.max(slot);
+        if sender.send(message).await.is_err() {
+            return Ok(());
+        }
+    }
+    warn!("grpc stream from {} ended", config.grpc_url);
+    Ok(())
+}
+
+/// Streams from `subscribe` and reconnects when a stream stops, until `sender` closes
+async fn run<F, S>(
+    config: Config,
+    sender: async_channel::Sender<Message>,
+    mut subscribe: impl FnMut() -> F,
+) where
+    F: Future<Output = anyhow::Result<S>>,
+    S: Stream<Item = Result<SubscribeUpdate, tonic::Status>> + Unpin,
+{
+    let mut progress = Progress::default();
+    // disconnects since the last stream that stayed up, for the reconnect backoff
+    let mut disconnects = 0u32;
+    loop {
+        info!("connecting to grpc source {}", config.grpc_url);
+        let connect_start = config.clock.now();
+        let out = match subscribe().await {
+            Ok(stream) => {
+                progress.subscribed = true;
+                feed_data(&config, stream, &mut progress, &sender).await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &out {
+            warn!("grpc source error: {err:?}");
+        }
+        if sender.is_closed() {
+            return;
+        }
+        let long_lived = progress.subscribed
+            && config.clock.now() - connect_start >= config.reconnect_backoff_reset_after;
+        if long_lived {
+            disconnects = 0;
+        }
+        if std::mem::take(&mut progress.subscribed) {
+            let last_slot = progress.last_slot;
+            let disconnected = Message::Disconnected {
+                last_slot,
+                url: config.grpc_url.clone(),
+                role: ConnectionRole::Main,
+            };
+            if sender.send(disconnected).await.is_err() {
+                return;
+            }
+        }
+        let backoff = reconnect_backoff(
+            config.reconnect_backoff_base,
+            config.reconnect_backoff_max,
+            disconnects,
+            out.is_ok(),
+        );
+        disconnects = disconnects.saturating_add(1);
+        config.clock.sleep(backoff).await;
+    }
+}
+
+pub fn start(
+    config: Config,
+    mango_oracles: Vec<Pubkey>,
+    sender: async_channel::Sender<Message>,
+) -> tokio::task::JoinHandle<()> {
+    let subscribe_config = config.clone();
+    tokio::spawn(run(config, sender, move || {
+        subscribe(subscribe_config.clone(), mango_oracles.clone())
+    }))
+}
+
+impl AccountSource for Config {
+    fn start(
+        self,
+        mango_oracles: Vec<Pubkey>,
+        sender: async_channel::Sender<Message>,
+    ) -> tokio::task::JoinHandle<()> {
+        start(self, mango_oracles, sender)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use solana_sdk::account::ReadableAccount;
+    use std::sync::atomic::AtomicUsize;
+    use yellowstone_grpc_proto::prelude::SubscribeUpdateAccountInfo;
+
+    #[test]
+    fn test_update_mapping() {
+        let pubkey = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let update = account_update(SubscribeUpdateAccount {
+            account: Some(SubscribeUpdateAccountInfo {
+                pubkey: pubkey.to_bytes().to_vec(),
+                lamports: 10,
+                owner: owner.to_bytes().to_vec(),
+                data: vec![1, 2, 3],
+                ..Default::default()
+            }),
+            slot: 5,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(update.pubkey, pubkey);
+        assert_eq!(update.slot, 5);
+        assert_eq!(update.account.owner(), &owner);
+        assert_eq!(update.account.lamports(), 10);
+        assert_eq!(update.account.data(), &[1, 2, 3]);
+
+        let slot = |status: CommitmentLevel, parent| {
+            slot_update(SubscribeUpdateSlot {
+                slot: 7,
+                parent,
+                status: status as i32,
+            })
+            .unwrap()
+        };
+        assert!(matches!(
+            slot(CommitmentLevel::Processed, Some(6)),
+            SlotUpdate::CreatedBank {
+                slot: 7,
+                parent: 6,
+                ..
+            }
+        ));
+        assert!(matches!(
+            slot(CommitmentLevel::Confirmed, Some(6)),
+            SlotUpdate::OptimisticConfirmation { slot: 7, .. }
+        ));
+        assert!(matches!(
+            slot(CommitmentLevel::Finalized, None),
+            SlotUpdate::Root { slot: 7, .. }
+        ));
+        assert!(slot_update(SubscribeUpdateSlot {
+            slot: 7,
+            parent: None,
+            status: 100,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_skip_undecodable_account() {
+        let config = Config::default();
+        let malformed = UpdateOneof::Account(SubscribeUpdateAccount {
+            account: Some(SubscribeUpdateAccountInfo {
+                pubkey: vec![1, 2, 3],
+                owner: Pubkey::new_unique().to_bytes().to_vec(),
+                ..Default::default()
+            }),
+            slot: 5,
+            ..Default::default()
+        });
+        assert!(update_message(&config, malformed).unwrap().is_none());
+        assert_eq!(config.metrics.decode_errors.load(Ordering::Relaxed), 1);
+
+        let slot = UpdateOneof::Slot(SubscribeUpdateSlot {
+            slot: 6,
+            parent: Some(5),
+            status: CommitmentLevel::Processed as i32,
+        });
+        assert!(matches!(
+            update_message(&config, slot).unwrap(),
+            Some((Message::Slot(_), 6))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_stream_error() {
+        let clock = Arc::new(MockClock::default());
+        let config = Config {
+            grpc_url: "http://localhost:10000".to_string(),
+            clock: clock.clone(),
+            ..Config::default()
+        };
+        let slot = |slot: u64| {
+            Ok(SubscribeUpdate {
+                update_oneof: Some(UpdateOneof::Slot(SubscribeUpdateSlot {
+                    slot,
+                    parent: Some(slot - 1),
+                    status: CommitmentLevel::Processed as i32,
+                })),
+                ..Default::default()
+            })
+        };
+        let mut streams = vec![
+            vec![slot(10), Err(tonic::Status::unavailable("node restarting"))],
+            vec![slot(11)],
+        ]
+        .into_iter();
+        let subscribes = Arc::new(AtomicUsize::new(0));
+        let (sender, receiver) = async_channel::unbounded();
+        tokio::spawn(run(config, sender, {
+            let subscribes = subscribes.clone();
+            move || {
+                subscribes.fetch_add(1, Ordering::Relaxed);
+                // the last stream stays open
+                let updates = streams.next().unwrap_or_default();
+                let stream = futures::stream::iter(updates).chain(futures::stream::pending());
+                futures::future::ready(anyhow::Ok(stream))
+            }
+        }));
+
+        let next_slot = |message: Result<Message, async_channel::RecvError>| match message {
+            Ok(Message::Slot(update)) => update.slot(),
+            _ => panic!("expected slot message"),
+        };
+        assert_eq!(next_slot(receiver.recv().await), 10);
+        match receiver.recv().await {
+            Ok(Message::Disconnected {
+                last_slot, role, ..
+            }) => {
+                assert_eq!(last_slot, 10);
+                assert_eq!(role, ConnectionRole::Main);
+            }
+            _ => panic!("expected disconnected message"),
+        }
+
+        // the stream failed, so the full reconnect_backoff_base
+        while clock.pending_sleeps() == 0 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(4));
+        tokio::task::yield_now().await;
+        assert_eq!(subscribes.load(Ordering::Relaxed), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(next_slot(receiver.recv().await), 11);
+        assert_eq!(subscribes.load(Ordering::Relaxed), 2);
+    }
+}
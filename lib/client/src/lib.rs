@@ -8,12 +8,16 @@ pub mod account_update_stream;
 pub mod chain_data;
 mod chain_data_fetcher;
 mod client;
+pub mod clock;
 pub mod confirm_transaction;
 mod context;
 pub mod error_tracking;
 pub mod gpa;
+pub mod grpc_source;
 pub mod health_cache;
 pub mod jupiter;
+#[cfg(any(test, feature = "mock-source"))]
+pub mod mock_source;
 pub mod perp_pnl;
 pub mod snapshot_source;
 mod util;
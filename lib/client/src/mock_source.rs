@@ -0,0 +1,199 @@
+//! Replays a scripted list of messages, for testing consumers without an rpc node
+//!
+//! A MockSource is an AccountSource like websocket_source::Config, so code that takes
+//! any source can be run against a script that contains exactly the out-of-order
+//! slots, dead slots and duplicate accounts a test needs.
+
+use solana_client::rpc_response::SlotUpdate;
+#[cfg(test)]
+use solana_sdk::account::AccountSharedData;
+use solana_sdk::pubkey::Pubkey;
+
+use std::{sync::Arc, time::Duration};
+
+use crate::account_update_stream::{AccountSource, AccountUpdate, Message};
+use crate::clock::{Clock, RealClock};
+
+enum Step {
+    Send(Message),
+    Delay(Duration),
+}
+
+/// Sends its messages in order, then ends and drops the sender
+///
+/// Receivers see the channel close once they read the last message, which makes
+/// `while let Ok(message) = receiver.recv().await` consume the whole script.
+pub struct MockSource {
+    steps: Vec<Step>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for MockSource {
+    fn default() -> Self {
+        Self {
+            steps: vec![],
+            clock: Arc::new(RealClock),
+        }
+    }
+}
+
+impl MockSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time source for the delays, use a MockClock to step through the script
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn message(mut self, message: Message) -> Self {
+        self.steps.push(Step::Send(message));
+        self
+    }
+
+    pub fn messages(mut self, messages: impl IntoIterator<Item = Message>) -> Self {
+        self.steps.extend(messages.into_iter().map(Step::Send));
+        self
+    }
+
+    pub fn account(self, update: AccountUpdate) -> Self {
+        self.message(Message::Account(update))
+    }
+
+    pub fn slot(self, update: SlotUpdate) -> Self {
+        self.message(Message::Slot(Arc::new(update)))
+    }
+
+    /// Wait before sending the next message
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.steps.push(Step::Delay(delay));
+        self
+    }
+}
+
+impl AccountSource for MockSource {
+    /// The oracles are ignored, the script decides what is sent
+    fn start(
+        self,
+        _mango_oracles: Vec<Pubkey>,
+        sender: async_channel::Sender<Message>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            for step in self.steps {
+                match step {
+                    Step::Send(message) => {
+                        if sender.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                    Step::Delay(delay) => self.clock.sleep(delay).await,
+                }
+            }
+        })
+    }
+}
+
+/// An account write with `lamports` and no data, to tell versions apart in tests
+#[cfg(test)]
+pub(crate) fn account_update(pubkey: Pubkey, slot: u64, lamports: u64) -> AccountUpdate {
+    AccountUpdate {
+        pubkey,
+        slot,
+        account: AccountSharedData::new(lamports, 0, &Pubkey::default()),
+        raw: None,
+        data_slice: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_data;
+    use crate::clock::MockClock;
+    use futures::FutureExt;
+    use solana_sdk::account::ReadableAccount;
+
+    #[tokio::test]
+    async fn test_replay_into_chain_data() {
+        let pubkey = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let source = MockSource::new()
+            .slot(SlotUpdate::Root {
+                slot: 10,
+                timestamp: 0,
+            })
+            .account(account_update(pubkey, 10, 1))
+            .account(account_update(other, 10, 5))
+            .slot(SlotUpdate::CreatedBank {
+                slot: 11,
+                parent: 10,
+                timestamp: 0,
+            })
+            .account(account_update(pubkey, 11, 2))
+            // slot 11 dies and the cluster continues from slot 10
+            .slot(SlotUpdate::Dead {
+                slot: 11,
+                timestamp: 0,
+                err: "replay failed".into(),
+            })
+            .slot(SlotUpdate::CreatedBank {
+                slot: 12,
+                parent: 10,
+                timestamp: 0,
+            })
+            // out of order and duplicated writes
+            .account(account_update(other, 12, 7))
+            .account(account_update(other, 10, 5))
+            .account(account_update(other, 12, 7))
+            .slot(SlotUpdate::Root {
+                slot: 12,
+                timestamp: 0,
+            });
+
+        let (sender, receiver) = async_channel::unbounded();
+        source.start(vec![], sender);
+        let mut chain = chain_data::ChainData::new();
+        let mut stale_writes = 0;
+        while let Ok(message) = receiver.recv().await {
+            stale_writes += message.update_chain_data(&mut chain);
+        }
+
+        assert_eq!(stale_writes, 1);
+        let stored = |pubkey| {
+            let data = chain.account(pubkey).unwrap();
+            (data.slot, data.account.lamports())
+        };
+        assert_eq!(stored(&pubkey), (10, 1));
+        assert_eq!(stored(&other), (12, 7));
+    }
+
+    #[tokio::test]
+    async fn test_delays_follow_the_clock() {
+        let clock = Arc::new(MockClock::default());
+        let (sender, receiver) = async_channel::unbounded();
+        MockSource::new()
+            .clock(clock.clone())
+            .slot(SlotUpdate::Root {
+                slot: 1,
+                timestamp: 0,
+            })
+            .delay(Duration::from_secs(10))
+            .slot(SlotUpdate::Root {
+                slot: 2,
+                timestamp: 0,
+            })
+            .start(vec![], sender);
+
+        assert!(matches!(receiver.recv().await, Ok(Message::Slot(_))));
+        while clock.pending_sleeps() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(receiver.recv().now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(10));
+        assert!(matches!(receiver.recv().await, Ok(Message::Slot(_))));
+        assert!(receiver.recv().await.is_err());
+    }
+}
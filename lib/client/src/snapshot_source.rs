@@ -55,6 +55,8 @@ impl AccountSnapshot {
                     .account
                     .decode()
                     .ok_or_else(|| anyhow::anyhow!("could not decode account"))?,
+                raw: None,
+                data_slice: None,
             });
         }
         Ok(())
@@ -74,6 +76,8 @@ impl AccountSnapshot {
                     account: ui_account
                         .decode()
                         .ok_or_else(|| anyhow::anyhow!("could not decode account"))?,
+                    raw: None,
+                    data_slice: None,
                 });
             }
         }
@@ -217,7 +221,7 @@ async fn feed_snapshots(
     sender
         .send(Message::Snapshot(snapshot.accounts))
         .await
-        .expect("sending must succeed");
+        .map_err(|_| anyhow::anyhow!("the message receiver was dropped"))?;
     Ok(())
 }
 
@@ -251,6 +255,10 @@ pub fn start(config: Config, mango_oracles: Vec<Pubkey>, sender: async_channel::
 
         loop {
             interval_between_snapshots.tick().await;
+            if sender.is_closed() {
+                info!("message receiver was dropped, stopping snapshot source");
+                break;
+            }
             if let Err(err) = feed_snapshots(&config, mango_oracles.clone(), &sender).await {
                 warn!("snapshot error: {:?}", err);
             } else {
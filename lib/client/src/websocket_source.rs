@@ -1,91 +1,2342 @@
-use jsonrpc_core::futures::StreamExt;
-use jsonrpc_core_client::transports::ws;
+use jsonrpc_core::futures::{future, FutureExt, SinkExt, StreamExt};
+use jsonrpc_core_client::{
+    transports::{duplex, http},
+    RpcError,
+};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{header, HeaderValue},
+    Message as WsMessage,
+};
 
-use solana_account_decoder::UiAccountEncoding;
+use solana_account_decoder::{UiAccount, UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
-    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcSignatureSubscribeConfig},
     rpc_filter::{Memcmp, RpcFilterType},
-    rpc_response::{RpcKeyedAccount, RpcResponseContext},
+    rpc_response::{
+        OptionalContext, Response, RpcKeyedAccount, RpcResponseContext, RpcSignatureResult,
+        SlotUpdate,
+    },
 };
+use solana_rpc::rpc::rpc_accounts_scan::AccountsScanClient;
 use solana_rpc::rpc_pubsub::RpcSolPubSubClient;
-use solana_sdk::{commitment_config::CommitmentConfig, pubkey::Pubkey};
+use solana_sdk::{
+    account::ReadableAccount, commitment_config::CommitmentConfig, pubkey::Pubkey,
+    signature::Signature,
+};
 
 use anyhow::Context;
-use std::time::Duration;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tokio_stream::StreamMap;
 use tracing::*;
 
-use crate::account_update_stream::{AccountUpdate, Message};
+use mango_v4::accounts_zerocopy::KeyedAccountSharedData;
+use mango_v4::state::{oracle_state_unchecked, OracleAccountInfos};
+
+use crate::account_update_stream::{
+    sort_account_batch, AccountSource, AccountUpdate, ConnectionRole, Message,
+};
+use crate::chain_data::SlotStatus;
+use crate::clock::{Clock, Interval, RealClock};
 use crate::AnyhowWrap;
 
+#[derive(Clone)]
 pub struct Config {
     pub rpc_ws_url: String,
+    /// alternatives to rpc_ws_url
+    ///
+    /// Every (re)connect goes to the endpoint with the best score among those that
+    /// did not fail to connect yet, see SourceHandle::endpoint_scores(). Until
+    /// anything is known about the endpoints that is rpc_ws_url. With
+    /// EndpointSelection::Priority the urls are tried in order instead.
+    pub fallback_ws_urls: Vec<String>,
+    /// what to do when rpc_ws_url and all fallback_ws_urls failed to connect
+    pub on_all_endpoints_failed: AllEndpointsFailed,
+    /// how the url for the next (re)connect is chosen, by score by default
+    pub endpoint_selection: EndpointSelection,
     pub serum_programs: Vec<Pubkey>,
     pub open_orders_authority: Pubkey,
+    /// how OpenOrders accounts of serum_programs are recognized, serum v3 by default
+    pub open_orders_filter: OpenOrdersFilter,
+
+    /// subscribe to these accounts individually, forwarded like program accounts
+    ///
+    /// Useful together with mango_program_subscription = false when only a known
+    /// set of mango accounts matters, that greatly reduces the data received.
+    pub watch_accounts: Vec<WatchSpec>,
+    /// subscribe to all accounts of the mango program
+    pub mango_program_subscription: bool,
+    /// restrict the mango program subscription to the accounts of this group
+    ///
+    /// The rpc node filters on the group pubkey at offset 8, right after the anchor
+    /// discriminator, where MangoAccount, Bank, PerpMarket, MintInfo, Serum3Market
+    /// and the other group-owned accounts store it. The Group account itself does
+    /// not match. None subscribes to the accounts of all groups.
+    pub group: Option<Pubkey>,
+    /// encoding of the account data in all account and program subscriptions
+    ///
+    /// Base64Zstd shrinks the mostly zero account data a lot, if the rpc node
+    /// supports it. Base64 by default. encoding_preference and WatchSpec::encoding
+    /// take precedence.
+    pub encoding: UiAccountEncoding,
+    /// encodings to try for the mango program subscription, in order
+    ///
+    /// Falls back to the next one when the rpc node rejects the subscription,
+    /// so [Base64Zstd, Base64] uses compression where it is supported. Empty,
+    /// the default, uses `encoding`.
+    pub encoding_preference: Vec<UiAccountEncoding>,
+    /// resubscribe to the mango program at confirmed if a processed subscription
+    /// sends no accounts for this long while slots keep advancing
+    ///
+    /// Some providers don't reliably send processed account notifications. The
+    /// processed subscription is replaced once the confirmed one delivers, which
+    /// is counted in Metrics::processed_stall_fallbacks and sent as
+    /// SourceEvent::ProcessedStallFallback. If the node rejects the confirmed
+    /// one, the connection stays at processed. The next connection starts at
+    /// processed again.
+    pub processed_stall_timeout: Option<Duration>,
+    /// reconnect when no subscription delivered anything for this long
+    pub ws_idle_timeout: Duration,
+
+    /// number of attempts at establishing the websocket connection (dns, tcp, tls, upgrade)
+    /// before moving on to the next of fallback_ws_urls
+    pub connect_attempts: u32,
+    /// timeout for a single connection attempt
+    pub connect_timeout: Duration,
+    /// delay between connection attempts
+    pub connect_retry_delay: Duration,
+    /// wait at least this long after an endpoint answered HTTP 429 to the
+    /// websocket upgrade, unless it sent a Retry-After header
+    pub rate_limit_backoff: Duration,
+    /// delay before reconnecting after a connection ended, doubled for every
+    /// further disconnect in a row up to reconnect_backoff_max
+    ///
+    /// Connections that ended with an error wait the full delay, streams that
+    /// were closed cleanly half of it.
+    pub reconnect_backoff_base: Duration,
+    pub reconnect_backoff_max: Duration,
+    /// a connection that received data for this long resets the backoff
+    pub reconnect_backoff_reset_after: Duration,
+    /// sent as User-Agent in the websocket handshake, defaults to mango-v4-client/<version>
+    ///
+    /// Some rpc providers use it to attribute traffic and allocate rate limits.
+    pub client_id: Option<String>,
+
+    /// if set, account updates owned by programs not in this set are dropped
+    ///
+    /// Guards against rpc bugs that return accounts of the wrong program. Note that
+    /// oracle owners (pyth, switchboard, ...) must be included when using this.
+    pub allowed_owners: Option<HashSet<Pubkey>>,
+
+    /// if set, stop reconnecting after this many reconnects and close the channel
+    ///
+    /// Useful for short-lived jobs that should fail instead of retrying forever.
+    pub max_lifetime_reconnects: Option<u32>,
+
+    /// open more websocket connections when the subscriptions don't fit into one
+    ///
+    /// For providers that limit subscriptions per connection. The oracles that
+    /// don't fit next to the mango, watched account, open orders and slot
+    /// subscriptions are split across additional connections, each with its own
+    /// slot subscription that is not forwarded. Watched signatures always go to the
+    /// main connection and are not counted. See SourceHandle::connections().
+    pub max_subscriptions_per_connection: Option<usize>,
+
+    /// what happens to account updates while the source is paused
+    pub pause_mode: PauseMode,
+    /// keep forwarding slot updates while paused
+    pub forward_slots_while_paused: bool,
+
+    /// which pubsub method to use for slot updates
+    pub slot_subscription_kind: SlotSubscriptionKind,
+
+    /// account updates with more data than this are dropped
+    ///
+    /// Protects against pathological payloads bloating ChainData.
+    pub max_account_bytes: Option<usize>,
+
+    /// at most this many distinct open orders accounts are tracked per connection
+    ///
+    /// When a new one exceeds the cap, the account with the oldest last update is
+    /// evicted: removed from SourceHandle::tracked_pubkeys() and counted in
+    /// Metrics::open_orders_evicted. It comes back with its next update. Consumers
+    /// can prune their chain data by checking tracked_pubkeys().
+    pub max_open_orders_accounts: Option<usize>,
+
+    /// drain ready slot updates before account updates in each iteration
+    ///
+    /// Without this, the account write for slot N can be forwarded before the slot
+    /// message that announces N. This is best-effort: slot updates that arrive
+    /// late at the websocket can still be forwarded after account writes for them.
+    pub prioritize_slot_updates: bool,
+
+    /// attach the account as sent by the rpc node to AccountUpdate::raw, for
+    /// integrations that need the exact encoded bytes
+    pub keep_raw: bool,
+
+    /// receive only this part of the data of mango program accounts
+    ///
+    /// Saves a lot of bandwidth, but only works with consumers that decode the
+    /// slice, see AccountUpdate::data_slice. Off by default.
+    pub mango_data_slice: Option<UiDataSliceConfig>,
+    /// like mango_data_slice, for the OpenOrders accounts of serum_programs
+    pub open_orders_data_slice: Option<UiDataSliceConfig>,
+
+    /// collect account updates for this long and send them as one Message::AccountBatch
+    ///
+    /// Only the newest update per account is kept, cutting the load from accounts
+    /// that change many times per slot. Slot messages are forwarded right away, so
+    /// they can arrive before the account updates of their slot. Updates still
+    /// collected when a connection drops are lost, like any that arrive after it,
+    /// see Message::Disconnected. Zero, the default, sends every update directly.
+    pub account_coalesce_interval: Duration,
+
+    /// Ignored, undecodable account updates are always skipped now
+    #[deprecated(
+        note = "undecodable account updates are always skipped, see Metrics::decode_errors"
+    )]
+    pub skip_undecodable_accounts: bool,
+    /// window over which Metrics::decode_skip_ratio is computed
+    pub decode_skip_window: Duration,
+    /// warn if the decode skip ratio in a window exceeds this
+    pub decode_skip_warn_ratio: f64,
+
+    /// if set, also connect to this url and cross-check the account data of both
+    ///
+    /// The first update for a pubkey and slot is forwarded, a later one from the
+    /// other rpc node is compared to it and a Message::Disagreement is sent if the
+    /// two differ. Guards against a single buggy or compromised rpc node.
+    ///
+    /// Slot messages are forwarded once per slot and status. A Message::Disconnected
+    /// is only sent when both nodes are disconnected, with the higher last_slot,
+    /// and only the first Message::Snapshot after that is forwarded.
+    pub dual_source_ws_url: Option<String>,
+
+    /// if set, fetch the mango program and open orders accounts from this rpc http
+    /// url after each (re)connect and forward them as Message::Snapshot
+    ///
+    /// Without it, accounts that aren't written stay unknown. The snapshot is
+    /// requested once the subscriptions are live, so no write falls in between.
+    /// It carries the slot of its getProgramAccounts response, later websocket
+    /// writes supersede it. A failed snapshot is logged and not retried until
+    /// the next connection.
+    pub backfill_rpc_http_url: Option<String>,
+
+    /// on shutdown, how long to keep sending messages that were already received
+    pub shutdown_drain_timeout: Duration,
+
+    /// how often to retry oracle subscriptions that the rpc node rejected
+    pub oracle_retry_interval: Duration,
+
+    /// reconnect if the newest processed slot is more than this many slots ahead
+    /// of the newest confirmed slot for longer than commitment_lag_timeout
+    ///
+    /// Catches rpc nodes that keep sending data but fall behind the cluster.
+    pub max_commitment_lag: Option<u64>,
+    pub commitment_lag_timeout: Duration,
+
+    /// reconnect after a connection was open this long, minus a random part of
+    /// max_connection_age_jitter
+    ///
+    /// Works around rpc providers whose subscriptions degrade over time. The old
+    /// connection keeps forwarding while the next one connects, if that fails it
+    /// stays and connecting is retried after connect_retry_delay. The new connection
+    /// only subscribes once the old one stopped, updates sent in between are missed
+    /// unless backfill_rpc_http_url is set: then the new connection's snapshot
+    /// covers them. start() warns when it is not.
+    pub max_connection_age: Option<Duration>,
+    pub max_connection_age_jitter: Duration,
+
+    /// count mango account writes whose slot is more than this many slots after
+    /// the newest oracle write, see Metrics::oracle_slot_lag_exceeded
+    ///
+    /// Valuations based on such an account and the known oracle prices mix data
+    /// from different slots.
+    pub max_oracle_slot_lag: Option<u64>,
+
+    /// only forward oracle updates whose price moved by more than this fraction,
+    /// like 0.0001 for 1bp, since the last forwarded update of the oracle
+    pub oracle_min_price_change: Option<f64>,
+    /// with oracle_min_price_change, forward an update of each oracle at least
+    /// this many slots after the last forwarded one
+    pub oracle_max_skipped_slots: u64,
+
+    /// commitment of the account subscriptions, unless overridden below
+    ///
+    /// confirmed avoids acting on oracle prices that flap at processed.
+    pub commitment: CommitmentConfig,
+    /// commitment for the mango program subscription, `commitment` if None
+    pub mango_commitment_override: Option<CommitmentConfig>,
+    /// commitment for the oracle subscriptions, `commitment` if None
+    pub oracle_commitment_override: Option<CommitmentConfig>,
+    /// commitment for the open orders subscriptions, `commitment` if None
+    pub open_orders_commitment_override: Option<CommitmentConfig>,
+    /// commitment at which watched signatures are reported
+    pub signature_commitment: CommitmentConfig,
+
+    pub metrics: Arc<Metrics>,
+    /// receives SourceEvents, events are dropped if the channel is full
+    pub events: Option<async_channel::Sender<SourceEvent>>,
+
+    /// time source for timeouts and watchdogs, replaceable for tests
+    pub clock: Arc<dyn Clock>,
 }
 
-async fn feed_data(
-    config: &Config,
-    mango_oracles: Vec<Pubkey>,
+impl Default for Config {
+    #[allow(deprecated)]
+    fn default() -> Self {
+        Self {
+            rpc_ws_url: String::new(),
+            fallback_ws_urls: vec![],
+            on_all_endpoints_failed: AllEndpointsFailed::Backoff(Duration::from_secs(5)),
+            endpoint_selection: EndpointSelection::BestScore,
+            serum_programs: vec![],
+            open_orders_authority: Pubkey::default(),
+            open_orders_filter: OpenOrdersFilter::default(),
+            watch_accounts: vec![],
+            mango_program_subscription: true,
+            group: None,
+            encoding: UiAccountEncoding::Base64,
+            encoding_preference: vec![],
+            processed_stall_timeout: None,
+            ws_idle_timeout: Duration::from_secs(60),
+            connect_attempts: 3,
+            connect_timeout: Duration::from_secs(10),
+            connect_retry_delay: Duration::from_millis(500),
+            rate_limit_backoff: Duration::from_secs(30),
+            reconnect_backoff_base: Duration::from_millis(500),
+            reconnect_backoff_max: Duration::from_secs(30),
+            reconnect_backoff_reset_after: Duration::from_secs(30),
+            client_id: None,
+            allowed_owners: None,
+            max_lifetime_reconnects: None,
+            max_subscriptions_per_connection: None,
+            pause_mode: PauseMode::Drop,
+            forward_slots_while_paused: true,
+            slot_subscription_kind: SlotSubscriptionKind::SlotsUpdates,
+            max_account_bytes: None,
+            max_open_orders_accounts: None,
+            prioritize_slot_updates: false,
+            keep_raw: false,
+            mango_data_slice: None,
+            open_orders_data_slice: None,
+            account_coalesce_interval: Duration::ZERO,
+            skip_undecodable_accounts: false,
+            decode_skip_window: Duration::from_secs(60),
+            decode_skip_warn_ratio: 0.01,
+            dual_source_ws_url: None,
+            backfill_rpc_http_url: None,
+            shutdown_drain_timeout: Duration::from_secs(2),
+            oracle_retry_interval: Duration::from_secs(10),
+            max_commitment_lag: None,
+            commitment_lag_timeout: Duration::from_secs(30),
+            max_connection_age: None,
+            max_connection_age_jitter: Duration::from_secs(60),
+            max_oracle_slot_lag: None,
+            oracle_min_price_change: None,
+            oracle_max_skipped_slots: 10,
+            commitment: CommitmentConfig::processed(),
+            mango_commitment_override: None,
+            oracle_commitment_override: None,
+            open_orders_commitment_override: None,
+            signature_commitment: CommitmentConfig::confirmed(),
+            metrics: Arc::new(Metrics::default()),
+            events: None,
+            clock: Arc::new(RealClock),
+        }
+    }
+}
+
+/// Counters about the websocket source, shared via Config::metrics
+#[derive(Default)]
+pub struct Metrics {
+    /// account updates dropped due to Config::max_account_bytes
+    pub oversized_accounts_dropped: AtomicU64,
+    /// account updates that were decoded successfully
+    pub accounts_decoded: AtomicU64,
+    /// account updates skipped because they couldn't be decoded
+    ///
+    /// A malformed account doesn't interrupt the other streams, only transport
+    /// errors make the source reconnect.
+    pub decode_errors: AtomicU64,
+    /// the same as decode_errors, the old name
+    #[deprecated(note = "renamed to decode_errors")]
+    pub accounts_decode_skipped: AtomicU64,
+    /// f64 bits, see decode_skip_ratio()
+    decode_skip_ratio: AtomicU64,
+    /// time from starting to connect until the subscriptions deliver data
+    pub ws_subscribe_setup_seconds: DurationHistogram,
+    /// how long sending to the consumer's channel blocked, high values mean
+    /// the consumer can't keep up; only recorded for bounded channels
+    pub channel_send_seconds: DurationHistogram,
+    /// mango account writes that were too far ahead of oracle data, see
+    /// Config::max_oracle_slot_lag
+    pub oracle_slot_lag_exceeded: AtomicU64,
+    /// slots between the latest mango account write and the newest oracle write
+    pub oracle_slot_lag: AtomicU64,
+    /// oracle updates forwarded and suppressed, see Config::oracle_min_price_change
+    pub oracle_updates_forwarded: AtomicU64,
+    pub oracle_updates_suppressed: AtomicU64,
+    /// messages missed by lagging broadcast subscribers, see start_broadcast()
+    pub broadcast_lagged: AtomicU64,
+    /// see Config::max_open_orders_accounts
+    pub open_orders_evicted: AtomicU64,
+    /// account updates older than what an earlier connection already delivered
+    pub accounts_behind_watermark_dropped: AtomicU64,
+    /// mango program subscriptions moved to confirmed, see Config::processed_stall_timeout
+    pub processed_stall_fallbacks: AtomicU64,
+    /// per stream health, see stream() and account_feed_lag()
+    pub mango_stream: StreamMetrics,
+    pub oracle_stream: StreamMetrics,
+    pub open_orders_stream: StreamMetrics,
+    pub slot_stream: StreamMetrics,
+}
+
+/// The subscription streams of a connection, for per stream metrics
+///
+/// Mango covers both the program subscription and Config::watch_accounts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamKind {
+    Mango,
+    Oracle,
+    OpenOrders,
+    Slot,
+}
+
+/// Health of one subscription stream, summed over all connections
+#[derive(Default)]
+pub struct StreamMetrics {
+    pub messages: AtomicU64,
+    /// highest slot of the stream's messages
+    pub last_slot: AtomicU64,
+    /// unix time in milliseconds of the last message, 0 before the first one
+    pub last_message_unix_ms: AtomicU64,
+}
+
+impl StreamMetrics {
+    fn record(&self, slot: u64) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.last_slot.fetch_max(slot, Ordering::Relaxed);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        self.last_message_unix_ms.store(now, Ordering::Relaxed);
+    }
+}
+
+/// Bucket upper bounds in seconds
+const DURATION_HISTOGRAM_BOUNDS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Histogram of durations with fixed buckets, in the style of prometheus
+pub struct DurationHistogram {
+    /// one per bound, and a final one for larger values
+    buckets: [AtomicU64; DURATION_HISTOGRAM_BOUNDS.len() + 1],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Default for DurationHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl DurationHistogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = DURATION_HISTOGRAM_BOUNDS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_HISTOGRAM_BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Cumulative counts per upper bound in seconds, the last bound is infinity
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let mut cumulative = 0;
+        DURATION_HISTOGRAM_BOUNDS
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect()
+    }
+}
+
+impl Metrics {
+    /// Count an account update that was skipped, see decode_errors
+    pub(crate) fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+        #[allow(deprecated)]
+        self.accounts_decode_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fraction of account updates that couldn't be decoded in the last full
+    /// Config::decode_skip_window
+    ///
+    /// A rising value hints at version skew or an account layout change.
+    pub fn decode_skip_ratio(&self) -> f64 {
+        f64::from_bits(self.decode_skip_ratio.load(Ordering::Relaxed))
+    }
+
+    pub fn stream(&self, kind: StreamKind) -> &StreamMetrics {
+        match kind {
+            StreamKind::Mango => &self.mango_stream,
+            StreamKind::Oracle => &self.oracle_stream,
+            StreamKind::OpenOrders => &self.open_orders_stream,
+            StreamKind::Slot => &self.slot_stream,
+        }
+    }
+
+    /// Slots between the newest slot update and the newest account update on any stream
+    ///
+    /// Quiet accounts don't change every slot, so a few slots of lag are normal. A
+    /// steadily growing value means the account notifications fall behind.
+    pub fn account_feed_lag(&self) -> u64 {
+        let newest_account_slot = [
+            &self.mango_stream,
+            &self.oracle_stream,
+            &self.open_orders_stream,
+        ]
+        .iter()
+        .map(|stream| stream.last_slot.load(Ordering::Relaxed))
+        .max()
+        .unwrap_or(0);
+        self.slot_stream
+            .last_slot
+            .load(Ordering::Relaxed)
+            .saturating_sub(newest_account_slot)
+    }
+}
+
+/// Decode outcomes in the current decode skip window
+struct DecodeStats {
+    window_start: Instant,
+    decoded: u64,
+    skipped: u64,
+}
+
+impl DecodeStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            decoded: 0,
+            skipped: 0,
+        }
+    }
+
+    fn record(&mut self, config: &Config, decoded: bool) {
+        let metrics = &config.metrics;
+        if decoded {
+            self.decoded += 1;
+            metrics.accounts_decoded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.skipped += 1;
+            metrics.record_decode_error();
+        }
+
+        let window = config.clock.now() - self.window_start;
+        if window < config.decode_skip_window {
+            return;
+        }
+        let ratio = self.skipped as f64 / (self.decoded + self.skipped) as f64;
+        metrics
+            .decode_skip_ratio
+            .store(ratio.to_bits(), Ordering::Relaxed);
+        if ratio > config.decode_skip_warn_ratio {
+            warn!(
+                "skipped {} of {} account updates due to decode failures in the last {}s",
+                self.skipped,
+                self.decoded + self.skipped,
+                window.as_secs()
+            );
+        }
+        *self = Self::new(config.clock.now());
+    }
+}
+
+/// Older measurements of an endpoint weigh half as much after this time
+const ENDPOINT_STATS_HALF_LIFE: Duration = Duration::from_secs(3600);
+
+/// Score of endpoints that were never connected to
+///
+/// Below the score of an endpoint that works well, so a good endpoint is not
+/// left for an unknown one, but above that of an endpoint that keeps failing.
+const UNTRIED_ENDPOINT_SCORE: f64 = 0.5;
+
+/// Commitment lag in slots that halves an endpoint's score
+const ENDPOINT_LAG_SCALE: f64 = 32.0;
+
+/// Rolling quality of one websocket endpoint, see SourceHandle::endpoint_scores()
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointScore {
+    pub url: String,
+    /// share of time connected, failed connects count as Config::connect_timeout
+    pub uptime: f64,
+    /// account and slot notifications per connected second
+    pub message_rate: f64,
+    /// share of account updates that could not be decoded
+    pub decode_skip_ratio: f64,
+    /// average number of slots the processed slot was ahead of the confirmed slot
+    pub commitment_lag: f64,
+    /// connect failures since the last successful connect
+    pub consecutive_failures: u32,
+    /// combined score in [0, 1], higher is better
+    ///
+    /// The product of the uptime, the share of decodable updates and the message
+    /// rate relative to the fastest endpoint, reduced by commitment lag.
+    pub score: f64,
+}
+
+/// Measurements of one connection to an endpoint, until it ends
+#[derive(Default)]
+struct EndpointSession {
+    accounts: u64,
+    skipped: u64,
+    slots: u64,
+    /// highest slot of any account or slot message
+    newest_slot: u64,
+    processed_slot: u64,
+    confirmed_slot: u64,
+    lag_sum: u64,
+    lag_samples: u64,
+}
+
+impl EndpointSession {
+    fn received(&self) -> u64 {
+        self.accounts + self.skipped + self.slots
+    }
+
+    fn slot(&mut self, message: &Message) {
+        self.slots += 1;
+        self.newest_slot = self.newest_slot.max(slot_message_slot(message));
+        match message {
+            Message::Slot(update) => match **update {
+                SlotUpdate::CreatedBank { slot, .. } => {
+                    self.processed_slot = self.processed_slot.max(slot)
+                }
+                SlotUpdate::OptimisticConfirmation { slot, .. } => {
+                    self.confirmed_slot = self.confirmed_slot.max(slot)
+                }
+                _ => return,
+            },
+            Message::SlotInfo(info) => {
+                self.processed_slot = self.processed_slot.max(info.slot);
+                self.confirmed_slot = self.confirmed_slot.max(info.root);
+            }
+            _ => return,
+        }
+        if self.confirmed_slot > 0 {
+            self.lag_sum += self.processed_slot.saturating_sub(self.confirmed_slot);
+            self.lag_samples += 1;
+        }
+    }
+}
+
+/// Exponentially decaying totals of the sessions of one endpoint
+#[derive(Clone, Debug, Default)]
+struct EndpointStats {
+    connected_secs: f64,
+    observed_secs: f64,
+    messages: f64,
+    accounts: f64,
+    skipped: f64,
+    lag_sum: f64,
+    lag_samples: f64,
+    consecutive_failures: u32,
+}
+
+impl EndpointStats {
+    fn decay(&mut self, elapsed: Duration) {
+        let factor = 0.5f64.powf(elapsed.as_secs_f64() / ENDPOINT_STATS_HALF_LIFE.as_secs_f64());
+        for total in [
+            &mut self.connected_secs,
+            &mut self.observed_secs,
+            &mut self.messages,
+            &mut self.accounts,
+            &mut self.skipped,
+            &mut self.lag_sum,
+            &mut self.lag_samples,
+        ] {
+            *total *= factor;
+        }
+    }
+
+    fn record_session(&mut self, connected: Duration, session: &EndpointSession) {
+        self.decay(connected);
+        self.connected_secs += connected.as_secs_f64();
+        self.observed_secs += connected.as_secs_f64();
+        self.messages += session.received() as f64;
+        self.accounts += session.accounts as f64;
+        self.skipped += session.skipped as f64;
+        self.lag_sum += session.lag_sum as f64;
+        self.lag_samples += session.lag_samples as f64;
+        self.consecutive_failures = 0;
+    }
+
+    fn record_failure(&mut self, downtime: Duration) {
+        self.decay(downtime);
+        self.observed_secs += downtime.as_secs_f64();
+        self.consecutive_failures += 1;
+    }
+}
+
+/// Scores of `urls`, in the same order
+fn endpoint_scores(stats: &HashMap<String, EndpointStats>, urls: &[String]) -> Vec<EndpointScore> {
+    let default_stats = EndpointStats::default();
+    let ratio = |a: f64, b: f64| if b > 0.0 { a / b } else { 0.0 };
+    let mut scores = urls
+        .iter()
+        .map(|url| {
+            let stats = stats.get(url).unwrap_or(&default_stats);
+            EndpointScore {
+                url: url.clone(),
+                uptime: ratio(stats.connected_secs, stats.observed_secs),
+                message_rate: ratio(stats.messages, stats.connected_secs),
+                decode_skip_ratio: ratio(stats.skipped, stats.accounts + stats.skipped),
+                commitment_lag: ratio(stats.lag_sum, stats.lag_samples),
+                consecutive_failures: stats.consecutive_failures,
+                score: if stats.observed_secs > 0.0 {
+                    0.0
+                } else {
+                    UNTRIED_ENDPOINT_SCORE
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let max_rate = scores
+        .iter()
+        .map(|score| score.message_rate)
+        .fold(0.0, f64::max);
+    for (url, score) in urls.iter().zip(scores.iter_mut()) {
+        if !stats.get(url).map_or(false, |s| s.observed_secs > 0.0) {
+            continue;
+        }
+        let relative_rate = if max_rate > 0.0 {
+            score.message_rate / max_rate
+        } else {
+            1.0
+        };
+        score.score = score.uptime * (1.0 - score.decode_skip_ratio) * relative_rate
+            / (1.0 + score.commitment_lag / ENDPOINT_LAG_SCALE);
+    }
+    scores
+}
+
+/// Index of the best scoring url among those that did not fail in this connect cycle
+///
+/// Ties go to the url that comes first, preferring rpc_ws_url.
+fn best_endpoint(
+    stats: &HashMap<String, EndpointStats>,
+    urls: &[String],
+    failed: &HashSet<usize>,
+) -> usize {
+    let mut best: Option<(usize, f64)> = None;
+    for (index, score) in endpoint_scores(stats, urls).iter().enumerate() {
+        if failed.contains(&index) {
+            continue;
+        }
+        if best.map_or(true, |(_, best_score)| score.score > best_score) {
+            best = Some((index, score.score));
+        }
+    }
+    best.map_or(0, |(index, _)| index)
+}
+
+/// Index of the url after `current` in priority order, skipping the `failed` ones
+///
+/// With `back_to_primary` that's rpc_ws_url, unless it failed in this connect cycle.
+fn priority_endpoint(
+    current: usize,
+    url_count: usize,
+    failed: &HashSet<usize>,
+    back_to_primary: bool,
+) -> usize {
+    if back_to_primary && !failed.contains(&0) {
+        return 0;
+    }
+    (1..=url_count)
+        .map(|step| (current + step) % url_count)
+        .find(|index| !failed.contains(index))
+        .unwrap_or(0)
+}
+
+/// Lifecycle and error events, for supervising the source without parsing logs
+///
+/// Sent to Config::events. The data itself only goes to the Message channel.
+#[derive(Clone, Debug)]
+pub enum SourceEvent {
+    Connected {
+        url: String,
+    },
+    ConnectFailed {
+        url: String,
+        error: String,
+    },
+    /// connecting failed for the main and all fallback urls
+    AllEndpointsFailed {
+        urls: Vec<String>,
+    },
+    /// a subscription stream ended, the source reconnects
+    StreamClosed {
+        url: String,
+        stream: &'static str,
+    },
+    /// the connection failed after it was established, the source reconnects
+    StreamError {
+        url: String,
+        error: String,
+    },
+    /// no data for a while, the source reconnects
+    Timeout {
+        url: String,
+    },
+    /// the mango program subscription at processed stalled and now runs at confirmed,
+    /// until the next connection, see Config::processed_stall_timeout
+    ProcessedStallFallback {
+        url: String,
+    },
+    /// an account update could not be decoded and was skipped
+    DecodeSkipped {
+        pubkey: String,
+    },
+    /// max_lifetime_reconnects was exceeded or all endpoints refused authorization
+    ///
+    /// The source stopped if it was the main connection. After an oracle shard
+    /// gave up, the main connection keeps running without the shard's oracles.
+    GaveUp {
+        reconnects: u32,
+        role: ConnectionRole,
+    },
+}
+
+fn emit(config: &Config, event: SourceEvent) {
+    if let Some(events) = &config.events {
+        // never block the data path for events
+        let _ = events.try_send(event);
+    }
+}
+
+/// An account in Config::watch_accounts, with optional subscription settings
+#[derive(Clone, Debug)]
+pub struct WatchSpec {
+    pub pubkey: Pubkey,
+    /// defaults to the mango commitment, see Config::mango_commitment_override
+    pub commitment: Option<CommitmentConfig>,
+    /// defaults to Config::encoding, must be a binary encoding
+    pub encoding: Option<UiAccountEncoding>,
+    /// forward only this part of the account data
+    pub data_slice: Option<UiDataSliceConfig>,
+}
+
+impl From<Pubkey> for WatchSpec {
+    fn from(pubkey: Pubkey) -> Self {
+        Self {
+            pubkey,
+            commitment: None,
+            encoding: None,
+            data_slice: None,
+        }
+    }
+}
+
+/// Called with the urls that were tried, see AllEndpointsFailed::Callback
+pub type AllEndpointsFailedCallback = Arc<dyn Fn(&[String]) + Send + Sync>;
+
+/// Behavior when connecting failed for every configured url in turn
+///
+/// This is always logged as an error, distinct from the warnings about a
+/// single endpoint failing.
+#[derive(Clone)]
+pub enum AllEndpointsFailed {
+    /// wait this long, then cycle through the urls again
+    Backoff(Duration),
+    /// call `callback`, for example to alert or emit a status event, then continue like Backoff
+    Callback {
+        backoff: Duration,
+        callback: AllEndpointsFailedCallback,
+    },
+}
+
+/// How run() picks among rpc_ws_url and fallback_ws_urls
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointSelection {
+    /// the best scoring url, see SourceHandle::endpoint_scores()
+    BestScore,
+    /// rpc_ws_url, then fallback_ws_urls in order: every reconnect moves on to the
+    /// next url, wrapping around
+    ///
+    /// Back at rpc_ws_url after a connection that stayed up for
+    /// Config::reconnect_backoff_reset_after, or once `primary_cooldown` passed
+    /// since leaving it.
+    Priority { primary_cooldown: Duration },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotSubscriptionKind {
+    /// slotsUpdatesSubscribe, sends Message::Slot with fine-grained slot events
+    SlotsUpdates,
+    /// slotSubscribe, sends Message::SlotInfo; supported by more rpc providers
+    Slot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseMode {
+    /// messages received while paused are discarded
+    Drop,
+    /// messages received while paused are kept and sent on resume
+    Buffer,
+}
+
+/// State shared between a running source and its SourceHandle
+struct SourceState {
+    paused: AtomicBool,
+    /// every connection flushes its paused buffer on a change
+    resumed_sender: watch::Sender<()>,
+    resumed_receiver: watch::Receiver<()>,
+    shutdown_sender: watch::Sender<bool>,
+    shutdown_receiver: watch::Receiver<bool>,
+    /// see SourceHandle::watch_signature()
+    watched_signatures: Mutex<HashSet<Signature>>,
+    signatures_changed_sender: watch::Sender<()>,
+    signatures_changed_receiver: watch::Receiver<()>,
+    /// see SourceHandle::tracked_pubkeys()
+    tracked_pubkeys: Mutex<HashSet<Pubkey>>,
+    /// by url, see SourceHandle::endpoint_scores()
+    endpoint_stats: Mutex<HashMap<String, EndpointStats>>,
+    /// see SourceHandle::add_oracle()
+    oracles: Mutex<Vec<Pubkey>>,
+    oracles_changed_sender: watch::Sender<()>,
+    oracles_changed_receiver: watch::Receiver<()>,
+}
+
+impl SourceState {
+    fn new(oracles: Vec<Pubkey>) -> Self {
+        let (resumed_sender, resumed_receiver) = watch::channel(());
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        let (signatures_changed_sender, signatures_changed_receiver) = watch::channel(());
+        let (oracles_changed_sender, oracles_changed_receiver) = watch::channel(());
+        Self {
+            paused: AtomicBool::new(false),
+            resumed_sender,
+            resumed_receiver,
+            shutdown_sender,
+            shutdown_receiver,
+            watched_signatures: Mutex::new(HashSet::new()),
+            signatures_changed_sender,
+            signatures_changed_receiver,
+            tracked_pubkeys: Mutex::new(HashSet::new()),
+            endpoint_stats: Mutex::new(HashMap::new()),
+            oracles: Mutex::new(oracles),
+            oracles_changed_sender,
+            oracles_changed_receiver,
+        }
+    }
+
+    fn update_oracles(&self, f: impl FnOnce(&mut Vec<Pubkey>)) {
+        f(&mut self.oracles.lock().unwrap());
+        let _ = self.oracles_changed_sender.send(());
+    }
+
+    fn update_watched_signatures(&self, f: impl FnOnce(&mut HashSet<Signature>)) {
+        f(&mut self.watched_signatures.lock().unwrap());
+        let _ = self.signatures_changed_sender.send(());
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn is_shut_down(&self) -> bool {
+        *self.shutdown_receiver.borrow()
+    }
+}
+
+/// Returned by start(), allows controlling the running source
+pub struct SourceHandle {
+    /// resolves when the source stops, see shutdown() and Config::max_lifetime_reconnects
+    pub task: tokio::task::JoinHandle<()>,
+    state: Arc<SourceState>,
+    connections: usize,
+}
+
+impl SourceHandle {
+    /// Stop forwarding messages while keeping the websocket connection alive
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Forward messages again, with PauseMode::Buffer every connection first sends
+    /// what it buffered
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::Relaxed);
+        let _ = self.state.resumed_sender.send(());
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.is_paused()
+    }
+
+    /// Stop the source
+    ///
+    /// Messages that were already received are still sent into the channel, for
+    /// at most Config::shutdown_drain_timeout. Await `task` to wait for that.
+    pub fn shutdown(&self) {
+        let _ = self.state.shutdown_sender.send(true);
+    }
+
+    /// Send a Message::Signature once the transaction is processed
+    ///
+    /// The signature stays watched across reconnects until it is reported.
+    pub fn watch_signature(&self, signature: Signature) {
+        self.state.update_watched_signatures(|signatures| {
+            signatures.insert(signature);
+        });
+    }
+
+    pub fn unwatch_signature(&self, signature: &Signature) {
+        self.state.update_watched_signatures(|signatures| {
+            signatures.remove(signature);
+        });
+    }
+
+    /// Subscribe to another oracle, for example of a newly listed market
+    ///
+    /// The running connection subscribes without reconnecting, and the oracle stays
+    /// subscribed across reconnects. With oracle shards it goes to the main connection,
+    /// regardless of Config::max_subscriptions_per_connection.
+    pub fn add_oracle(&self, oracle: Pubkey) {
+        self.state.update_oracles(|oracles| {
+            if !oracles.contains(&oracle) {
+                oracles.push(oracle);
+            }
+        });
+    }
+
+    /// Unsubscribe from an oracle passed to start() or add_oracle()
+    pub fn remove_oracle(&self, oracle: &Pubkey) {
+        self.state.update_oracles(|oracles| {
+            oracles.retain(|o| o != oracle);
+        });
+    }
+
+    /// Accounts that were forwarded or buffered since the last (re)connect
+    ///
+    /// Reset whenever the websocket connection is reestablished, in dual source
+    /// mode or with oracle shards when any of the connections is.
+    pub fn tracked_pubkeys(&self) -> HashSet<Pubkey> {
+        self.state.tracked_pubkeys.lock().unwrap().clone()
+    }
+
+    /// Number of websocket connections the source keeps open
+    ///
+    /// More than one in dual source mode or when Config::max_subscriptions_per_connection
+    /// made it shard the oracles.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Quality of the configured endpoints, best first
+    ///
+    /// Reconnects go to the best scoring endpoint. In dual source mode this
+    /// includes the secondary endpoint.
+    pub fn endpoint_scores(&self) -> Vec<EndpointScore> {
+        let stats = self.state.endpoint_stats.lock().unwrap();
+        let urls = stats.keys().cloned().collect::<Vec<_>>();
+        let mut scores = endpoint_scores(&stats, &urls);
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        scores
+    }
+}
+
+/// Offset of the group pubkey in group-owned mango accounts, after the discriminator
+const MANGO_GROUP_OFFSET: usize = 8;
+
+/// Contents of a config file read by Config::from_file()
+#[derive(serde::Deserialize)]
+struct ConfigFile {
+    rpc_ws_url: String,
+    #[serde(default)]
+    serum_programs: Vec<String>,
+    open_orders_authority: String,
+    /// overrides for serum-compatible dexes, see OpenOrdersFilter
+    open_orders_data_size: Option<u64>,
+    open_orders_owner_offset: Option<usize>,
+    group: Option<String>,
+    #[serde(default)]
+    oracles: Vec<String>,
+}
+
+/// The 1-based line of `value` in the config text, the first exact quoted occurrence
+/// at or after the line that sets `field`, for toml `field = ` or json `"field":`
+fn config_value_line(content: &str, field: &str, value: &str) -> Option<usize> {
+    let lines = content.lines().collect::<Vec<_>>();
+    let sets_field = |line: &str| {
+        let line = line.trim_start();
+        let rest = if let Some(rest) = line.strip_prefix(field) {
+            rest
+        } else if let Some(rest) = line.strip_prefix(&format!("\"{field}\"")) {
+            rest
+        } else {
+            return false;
+        };
+        let rest = rest.trim_start();
+        rest.starts_with('=') || rest.starts_with(':')
+    };
+    let field_line = lines.iter().position(|line| sets_field(line))?;
+    let quoted = [format!("\"{value}\""), format!("'{value}'")];
+    lines[field_line..]
+        .iter()
+        .position(|line| quoted.iter().any(|quoted| line.contains(quoted.as_str())))
+        .map(|offset| field_line + offset + 1)
+}
+
+impl Config {
+    /// The data slice the subscription that delivers `update` was set up with
+    fn data_slice_for(&self, update: &AccountUpdate) -> Option<UiDataSliceConfig> {
+        if let Some(spec) = self
+            .watch_accounts
+            .iter()
+            .find(|spec| spec.pubkey == update.pubkey)
+        {
+            spec.data_slice
+        } else if update.is_mango(&mango_v4::id()) {
+            self.mango_data_slice
+        } else if self.serum_programs.contains(update.owner()) {
+            self.open_orders_data_slice
+        } else {
+            None
+        }
+    }
+
+    /// Default config for a single websocket url
+    pub fn from_url(rpc_ws_url: impl Into<String>) -> Self {
+        Self {
+            rpc_ws_url: rpc_ws_url.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Default config for websocket urls in priority order, see EndpointSelection::Priority
+    ///
+    /// The first url becomes rpc_ws_url, the others fallback_ws_urls.
+    pub fn from_urls(urls: Vec<String>, primary_cooldown: Duration) -> anyhow::Result<Self> {
+        let mut urls = urls.into_iter();
+        let rpc_ws_url = urls
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no websocket urls given"))?;
+        Ok(Self {
+            rpc_ws_url,
+            fallback_ws_urls: urls.collect(),
+            endpoint_selection: EndpointSelection::Priority { primary_cooldown },
+            ..Default::default()
+        })
+    }
+
+    /// Filters of the mango program subscription, see Config::group
+    fn mango_program_filters(&self) -> Option<Vec<RpcFilterType>> {
+        self.group.map(|group| {
+            vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
+                MANGO_GROUP_OFFSET,
+                group.to_bytes().to_vec(),
+            ))]
+        })
+    }
+
+    pub fn mango_commitment(&self) -> CommitmentConfig {
+        self.mango_commitment_override.unwrap_or(self.commitment)
+    }
+
+    pub fn oracle_commitment(&self) -> CommitmentConfig {
+        self.oracle_commitment_override.unwrap_or(self.commitment)
+    }
+
+    pub fn open_orders_commitment(&self) -> CommitmentConfig {
+        self.open_orders_commitment_override
+            .unwrap_or(self.commitment)
+    }
+
+    /// Whether the streams use different commitment levels
+    ///
+    /// Then an oracle price can be from a slot that the mango accounts haven't
+    /// reached yet, or the other way around. Cautious consumers may refuse to
+    /// liquidate in that case.
+    pub fn has_commitment_mismatch(&self) -> bool {
+        let mango = self.mango_commitment();
+        mango != self.oracle_commitment() || mango != self.open_orders_commitment()
+    }
+
+    /// Read the websocket url, program ids, open orders authority and oracle list
+    /// from a toml file, or a json file if the path ends in .json
+    ///
+    /// Optionally open_orders_data_size and open_orders_owner_offset adjust the
+    /// open_orders_filter. All other fields are defaulted.
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> anyhow::Result<(Config, Vec<Pubkey>)> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading websocket config {}", path.display()))?;
+        let file: ConfigFile = if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(&content)
+                .with_context(|| format!("parsing websocket config {}", path.display()))?
+        } else {
+            toml::from_str(&content)
+                .with_context(|| format!("parsing websocket config {}", path.display()))?
+        };
+
+        let parse_pubkey = |field: &str, value: &str| -> anyhow::Result<Pubkey> {
+            Pubkey::from_str(value).map_err(|err| {
+                // serde doesn't track positions, find the offending entry in the text
+                let line = config_value_line(&content, field, value)
+                    .map(|line| format!(", line {line}"))
+                    .unwrap_or_default();
+                anyhow::anyhow!(
+                    "{}{line}: bad pubkey \"{value}\" in {field}: {err}",
+                    path.display()
+                )
+            })
+        };
+        let serum_programs = file
+            .serum_programs
+            .iter()
+            .map(|pk| parse_pubkey("serum_programs", pk))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let open_orders_authority =
+            parse_pubkey("open_orders_authority", &file.open_orders_authority)?;
+        let oracles = file
+            .oracles
+            .iter()
+            .map(|pk| parse_pubkey("oracles", pk))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let group = file
+            .group
+            .as_deref()
+            .map(|group| parse_pubkey("group", group))
+            .transpose()?;
+
+        let mut open_orders_filter = OpenOrdersFilter::default();
+        if let Some(data_size) = file.open_orders_data_size {
+            open_orders_filter = open_orders_filter.data_size(data_size);
+        }
+        if let Some(owner_offset) = file.open_orders_owner_offset {
+            open_orders_filter = open_orders_filter.owner_offset(owner_offset);
+        }
+
+        let config = Config {
+            rpc_ws_url: file.rpc_ws_url,
+            serum_programs,
+            open_orders_authority,
+            open_orders_filter,
+            group,
+            ..Default::default()
+        };
+        Ok((config, oracles))
+    }
+}
+
+/// Subscription ids assigned by the rpc node, learned from the websocket traffic
+///
+/// The jsonrpc client doesn't expose them, but rpc providers need them to find
+/// a subscription in their logs.
+#[derive(Default)]
+struct SubscriptionIds {
+    /// request id to subscription name, for subscribe requests without response
+    pending: HashMap<u64, String>,
+    /// subscription name, like "programSubscribe <program id>", to subscription id
+    ids: HashMap<String, u64>,
+}
+
+impl SubscriptionIds {
+    fn name(method: &str, target: Option<&str>) -> String {
+        match target {
+            Some(target) => format!("{method} {target}"),
+            None => method.to_string(),
+        }
+    }
+
+    fn outgoing(&mut self, text: &str) {
+        let request: serde_json::Value = match serde_json::from_str(text) {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let (method, id) = match (request["method"].as_str(), request["id"].as_u64()) {
+            (Some(method), Some(id)) if method.ends_with("Subscribe") => (method, id),
+            _ => return,
+        };
+        let name = Self::name(method, request["params"][0].as_str());
+        self.pending.insert(id, name);
+    }
+
+    fn incoming(&mut self, text: &str) {
+        // subscribe responses are short, don't parse every notification
+        if self.pending.is_empty() || text.len() > 256 {
+            return;
+        }
+        let response: serde_json::Value = match serde_json::from_str(text) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        let (id, subscription) = match (response["id"].as_u64(), response["result"].as_u64()) {
+            (Some(id), Some(subscription)) => (id, subscription),
+            _ => return,
+        };
+        if let Some(name) = self.pending.remove(&id) {
+            self.ids.insert(name, subscription);
+        }
+    }
+
+    /// The subscription ids for a method and targets, for logging
+    fn describe(&self, method: &str, targets: &[String]) -> String {
+        let describe_one = |target: Option<&str>| match self.ids.get(&Self::name(method, target)) {
+            Some(id) => id.to_string(),
+            None => "unknown".into(),
+        };
+        if targets.is_empty() {
+            return describe_one(None);
+        }
+        targets
+            .iter()
+            .map(|target| describe_one(Some(target)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+struct Connection {
+    client: RpcSolPubSubClient,
+    subscription_ids: Arc<Mutex<SubscriptionIds>>,
+}
+
+/// The rpc node answered the websocket upgrade request with an HTTP error
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    /// 401 or 403, usually a missing or wrong api key in the url
+    #[error("websocket endpoint {url} refused authorization (HTTP {status})")]
+    Unauthorized { url: String, status: u16 },
+    /// 429, the endpoint limits the number of connections
+    #[error("websocket endpoint {url} is rate limiting connections (HTTP 429)")]
+    RateLimited {
+        url: String,
+        /// from the Retry-After header
+        retry_after: Option<Duration>,
+    },
+    #[error("websocket upgrade to {url} failed with HTTP {status}")]
+    Rejected { url: String, status: u16 },
+}
+
+impl HandshakeError {
+    fn from_response<T>(
+        url: &str,
+        response: &tokio_tungstenite::tungstenite::http::Response<T>,
+    ) -> Self {
+        let url = url.to_string();
+        let status = response.status().as_u16();
+        match status {
+            401 | 403 => Self::Unauthorized { url, status },
+            429 => Self::RateLimited {
+                url,
+                retry_after: response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs),
+            },
+            _ => Self::Rejected { url, status },
+        }
+    }
+
+    /// Whether retrying right away is pointless
+    fn is_persistent(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::Unauthorized { .. } | HandshakeError::RateLimited { .. })
+        )
+    }
+}
+
+/// Do the websocket handshake, sending `client_id` as User-Agent, and set up the pubsub client
+///
+/// jsonrpc's ws transport can't send custom headers, so the handshake is done
+/// by tokio-tungstenite and the connection is then driven by jsonrpc's duplex transport.
+async fn connect_pubsub(url: &str, client_id: &str) -> anyhow::Result<Connection> {
+    let mut request = url.into_client_request()?;
+    request
+        .headers_mut()
+        .insert(header::USER_AGENT, HeaderValue::from_str(client_id)?);
+    let (ws_stream, _) = match tokio_tungstenite::connect_async(request).await {
+        Ok(connected) => connected,
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+            return Err(HandshakeError::from_response(url, &response).into());
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let subscription_ids = Arc::new(Mutex::new(SubscriptionIds::default()));
+    let (sink, stream) = ws_stream.split();
+    let outgoing_ids = subscription_ids.clone();
+    let sink = sink
+        .sink_map_err(|err| RpcError::Other(Box::new(err)))
+        .with(move |text: String| {
+            outgoing_ids.lock().unwrap().outgoing(&text);
+            future::ready(Ok::<_, RpcError>(WsMessage::Text(text)))
+        });
+    let incoming_ids = subscription_ids.clone();
+    let stream = stream
+        .take_while(|message| future::ready(message.is_ok()))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(WsMessage::Text(text)) => Some(text),
+                _ => None,
+            })
+        })
+        .inspect(move |text| incoming_ids.lock().unwrap().incoming(text));
+    let (duplex, channel) = duplex::duplex(Box::pin(sink), Box::pin(stream));
+    tokio::spawn(duplex.map(|result| {
+        if let Err(err) = result {
+            debug!("websocket transport stopped: {err:?}");
+        }
+    }));
+    Ok(Connection {
+        client: channel.into(),
+        subscription_ids,
+    })
+}
+
+fn default_client_id() -> String {
+    format!("mango-v4-client/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Establish the websocket connection, retrying transient failures
+///
+/// This is separate from the reconnect loop in start(): it only covers getting
+/// a connection in the first place, which often fails on cold starts while dns
+/// or the rpc node are still warming up.
+async fn connect(config: &Config) -> anyhow::Result<Connection> {
+    // an error here means the url is bad, retrying won't help
+    config
+        .rpc_ws_url
+        .as_str()
+        .into_client_request()
+        .with_context(|| format!("bad websocket url {}", config.rpc_ws_url))?;
+    let client_id = config.client_id.clone().unwrap_or_else(default_client_id);
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let connect = connect_pubsub(&config.rpc_ws_url, &client_id);
+        let result = match timeout(config, config.connect_timeout, connect).await {
+            Some(result) => result,
+            None => Err(anyhow::anyhow!(
+                "timeout after {}ms",
+                config.connect_timeout.as_millis()
+            )),
+        };
+        match result {
+            Ok(connection) => return Ok(connection),
+            Err(err)
+                if attempt < config.connect_attempts && !HandshakeError::is_persistent(&err) =>
+            {
+                debug!(
+                    "websocket connect to {} failed (attempt {attempt}), retrying: {err:?}",
+                    config.rpc_ws_url
+                );
+                config.clock.sleep(config.connect_retry_delay).await;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!(
+                        "connecting to {} failed after {attempt} attempts",
+                        config.rpc_ws_url
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Returns false if an account update should not be forwarded
+fn accept_account(config: &Config, update: &AccountUpdate) -> bool {
+    if let Some(allowed_owners) = config.allowed_owners.as_ref() {
+        if !allowed_owners.contains(update.owner()) {
+            warn!(
+                "dropping update for {} with unexpected owner {}",
+                update.pubkey,
+                update.owner()
+            );
+            return false;
+        }
+    }
+    if let Some(max_account_bytes) = config.max_account_bytes {
+        let size = update.account.data().len();
+        if size > max_account_bytes {
+            warn!(
+                "dropping update for {} with {size} bytes of data, limit is {max_account_bytes}",
+                update.pubkey
+            );
+            config
+                .metrics
+                .oversized_accounts_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+    }
+    true
+}
+
+/// Which of the oracles in SourceState::oracles a connection subscribes to
+#[derive(Clone)]
+enum OracleSelection {
+    /// all but those of the oracle shards, including the ones added later
+    AllExcept(Arc<HashSet<Pubkey>>),
+    /// those of an oracle shard that weren't removed
+    Only(Arc<HashSet<Pubkey>>),
+}
+
+impl OracleSelection {
+    fn select(&self, oracles: &[Pubkey]) -> Vec<Pubkey> {
+        oracles
+            .iter()
+            .filter(|oracle| match self {
+                Self::AllExcept(excluded) => !excluded.contains(oracle),
+                Self::Only(included) => included.contains(oracle),
+            })
+            .copied()
+            .collect()
+    }
+}
+
+/// Sends messages from feed_data into the channel
+struct Forwarder<'a> {
+    config: &'a Config,
+    state: &'a SourceState,
+    role: ConnectionRole,
+    session: &'a mut EndpointSession,
     sender: async_channel::Sender<Message>,
+    paused_buffer: Vec<Message>,
+    decode_stats: DecodeStats,
+    /// start time and number of subscriptions, until the first slot message arrives
+    pending_setup: Option<(Instant, usize)>,
+    newest_oracle_slot: Option<u64>,
+    /// highest slot in slot messages
+    newest_slot: u64,
+    /// price and slot of the last forwarded update per oracle
+    oracle_last_forwarded: HashMap<Pubkey, (f64, u64)>,
+    open_orders_lru: SlotLru,
+    /// newest update per account, see Config::account_coalesce_interval
+    coalesced: HashMap<Pubkey, AccountUpdate>,
+    /// account updates from behind these are dropped
+    ///
+    /// The watermarks of the connections before this one. A node that is behind
+    /// would otherwise bring back older account states after a reconnect. They're
+    /// also sent as min_context_slot, though rpc nodes may ignore that for subscriptions.
+    min_context_slots: Watermarks,
+    /// updated with every forwarded account update
+    watermarks: &'a mut Watermarks,
+}
+
+impl<'a> Forwarder<'a> {
+    /// Accounts of the mango program subscription or of Config::watch_accounts
+    async fn rpc_account(
+        &mut self,
+        response: Response<RpcKeyedAccount>,
+        commitment: CommitmentConfig,
+    ) -> anyhow::Result<()> {
+        if let Some(update) = self.decode(response) {
+            self.account(update, StreamKind::Mango, commitment).await?;
+        }
+        Ok(())
+    }
+
+    /// None if the account could not be decoded, see Metrics::decode_errors
+    fn decode(&mut self, response: Response<RpcKeyedAccount>) -> Option<AccountUpdate> {
+        let pubkey = response.value.pubkey.clone();
+        match AccountUpdate::from_rpc(response, self.config.keep_raw) {
+            Ok(mut update) => {
+                update.data_slice = self.config.data_slice_for(&update);
+                self.decode_stats.record(self.config, true);
+                self.session.accounts += 1;
+                self.session.newest_slot = self.session.newest_slot.max(update.slot);
+                Some(update)
+            }
+            Err(err) => {
+                self.decode_stats.record(self.config, false);
+                self.session.skipped += 1;
+                // a malformed stream fails on every update, DecodeStats warns about the ratio
+                debug!("skipping account update for {pubkey} that could not be decoded: {err:?}");
+                emit(self.config, SourceEvent::DecodeSkipped { pubkey });
+                None
+            }
+        }
+    }
+
+    async fn oracle_account(&mut self, response: Response<RpcKeyedAccount>) -> anyhow::Result<()> {
+        let slot = response.context.slot;
+        self.newest_oracle_slot = Some(self.newest_oracle_slot.unwrap_or(0).max(slot));
+        if let Some(update) = self.decode(response) {
+            if self.oracle_should_forward(&update) {
+                let commitment = self.config.oracle_commitment();
+                self.account(update, StreamKind::Oracle, commitment).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Downsampling of oracle updates, see Config::oracle_min_price_change
+    fn oracle_should_forward(&mut self, update: &AccountUpdate) -> bool {
+        let min_change = match self.config.oracle_min_price_change {
+            Some(min_change) => min_change,
+            None => return true,
+        };
+        let keyed = KeyedAccountSharedData::new(update.pubkey, update.account.clone());
+        // the decimals scale all prices of an oracle the same, they don't matter here
+        let price = match oracle_state_unchecked(&OracleAccountInfos::from_reader(&keyed), 6) {
+            Ok(state) => state.price.to_num::<f64>(),
+            // can't tell how much the price changed
+            Err(_) => return true,
+        };
+
+        let metrics = &self.config.metrics;
+        if let Some(&(last_price, last_slot)) = self.oracle_last_forwarded.get(&update.pubkey) {
+            let change = ((price - last_price) / last_price).abs();
+            let stale = update.slot >= last_slot + self.config.oracle_max_skipped_slots;
+            if change <= min_change && !stale {
+                metrics
+                    .oracle_updates_suppressed
+                    .fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        self.oracle_last_forwarded
+            .insert(update.pubkey, (price, update.slot));
+        metrics
+            .oracle_updates_forwarded
+            .fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn check_oracle_slot_lag(&self, update: &AccountUpdate) {
+        let (max_lag, oracle_slot) =
+            match (self.config.max_oracle_slot_lag, self.newest_oracle_slot) {
+                (Some(max_lag), Some(oracle_slot)) => (max_lag, oracle_slot),
+                _ => return,
+            };
+        if !update.is_mango(&mango_v4::id()) {
+            return;
+        }
+        let metrics = &self.config.metrics;
+        let lag = update.slot.saturating_sub(oracle_slot);
+        metrics.oracle_slot_lag.store(lag, Ordering::Relaxed);
+        if lag > max_lag {
+            metrics
+                .oracle_slot_lag_exceeded
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "mango account {} at slot {} is {lag} slots ahead of the newest oracle data",
+                update.pubkey, update.slot
+            );
+        }
+    }
+
+    async fn open_orders_account(
+        &mut self,
+        response: Response<RpcKeyedAccount>,
+    ) -> anyhow::Result<()> {
+        let update = match self.decode(response) {
+            Some(update) => update,
+            None => return Ok(()),
+        };
+        if let Some(max_accounts) = self.config.max_open_orders_accounts {
+            let evicted = self
+                .open_orders_lru
+                .touch(update.pubkey, update.slot, max_accounts);
+            if !evicted.is_empty() {
+                let mut tracked = self.state.tracked_pubkeys.lock().unwrap();
+                for pubkey in evicted.iter() {
+                    tracked.remove(pubkey);
+                }
+                self.config
+                    .metrics
+                    .open_orders_evicted
+                    .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+            }
+        }
+        let commitment = self.config.open_orders_commitment();
+        self.account(update, StreamKind::OpenOrders, commitment)
+            .await
+    }
+
+    /// Forward a getProgramAccounts response as one Message::Snapshot
+    async fn snapshot(
+        &mut self,
+        response: Response<Vec<RpcKeyedAccount>>,
+        open_orders: bool,
+    ) -> anyhow::Result<()> {
+        let mut updates = vec![];
+        for keyed_account in response.value {
+            let keyed_response = Response {
+                context: response.context.clone(),
+                value: keyed_account,
+            };
+            if let Some(update) = self.decode(keyed_response) {
+                if accept_account(self.config, &update) {
+                    updates.push(update);
+                }
+            }
+        }
+
+        if let (true, Some(max_accounts)) = (open_orders, self.config.max_open_orders_accounts) {
+            let mut evicted = HashSet::new();
+            for update in updates.iter() {
+                evicted.extend(self.open_orders_lru.touch(
+                    update.pubkey,
+                    update.slot,
+                    max_accounts,
+                ));
+            }
+            updates.retain(|update| !evicted.contains(&update.pubkey));
+            let mut tracked = self.state.tracked_pubkeys.lock().unwrap();
+            for pubkey in evicted.iter() {
+                tracked.remove(pubkey);
+            }
+            self.config
+                .metrics
+                .open_orders_evicted
+                .fetch_add(evicted.len() as u64, Ordering::Relaxed);
+        }
+
+        info!(
+            "backfilled {} accounts at slot {}",
+            updates.len(),
+            response.context.slot
+        );
+        self.state
+            .tracked_pubkeys
+            .lock()
+            .unwrap()
+            .extend(updates.iter().map(|update| update.pubkey));
+        self.send(Message::Snapshot(updates)).await
+    }
+
+    async fn account(
+        &mut self,
+        update: AccountUpdate,
+        stream: StreamKind,
+        commitment: CommitmentConfig,
+    ) -> anyhow::Result<()> {
+        let min_slot = self.min_context_slots.get(stream, commitment);
+        if is_behind_watermark(min_slot, update.slot) {
+            debug!(
+                "dropping update for {} at slot {}, already forwarded slot {:?}",
+                update.pubkey, update.slot, min_slot
+            );
+            self.config
+                .metrics
+                .accounts_behind_watermark_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        self.check_oracle_slot_lag(&update);
+        if accept_account(self.config, &update) {
+            self.watermarks.record(stream, commitment, update.slot);
+            self.state
+                .tracked_pubkeys
+                .lock()
+                .unwrap()
+                .insert(update.pubkey);
+            if self.config.account_coalesce_interval.is_zero() {
+                self.send(Message::Account(update)).await?;
+            } else {
+                // a later update in the same slot is the newer one
+                match self.coalesced.get(&update.pubkey) {
+                    Some(existing) if existing.slot > update.slot => {}
+                    _ => {
+                        self.coalesced.insert(update.pubkey, update);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the collected account updates as one Message::AccountBatch
+    async fn flush_coalesced(&mut self) -> anyhow::Result<()> {
+        if self.coalesced.is_empty() {
+            return Ok(());
+        }
+        let mut batch = std::mem::take(&mut self.coalesced)
+            .into_values()
+            .collect::<Vec<_>>();
+        sort_account_batch(&mut batch);
+        self.send(Message::AccountBatch(batch)).await
+    }
+
+    async fn slot(&mut self, message: Message) -> anyhow::Result<()> {
+        let slot = slot_message_slot(&message);
+        self.newest_slot = self.newest_slot.max(slot);
+        self.session.slot(&message);
+        if self.role == ConnectionRole::OracleShard {
+            self.pending_setup = None;
+            return Ok(());
+        }
+
+        // The slot subscription is requested last. Since the rpc node handles
+        // requests in order, its first message means all subscriptions are live.
+        if let Some((setup_start, subscriptions)) = self.pending_setup.take() {
+            let setup_duration = self.config.clock.now() - setup_start;
+            self.config
+                .metrics
+                .ws_subscribe_setup_seconds
+                .observe(setup_duration);
+            info!(
+                "websocket connected to {} with {subscriptions} subscriptions, setup took {}ms",
+                self.config.rpc_ws_url,
+                setup_duration.as_millis()
+            );
+        }
+
+        if self.state.is_paused() && self.config.forward_slots_while_paused {
+            send_timed(self.config, &self.sender, message).await?;
+        } else {
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        if self.state.is_paused() {
+            if self.config.pause_mode == PauseMode::Buffer {
+                self.paused_buffer.push(message);
+            }
+            return Ok(());
+        }
+        self.flush_paused().await?;
+        send_timed(self.config, &self.sender, message).await?;
+        Ok(())
+    }
+
+    /// Send messages buffered while paused, if no longer paused
+    async fn flush_paused(&mut self) -> anyhow::Result<()> {
+        if self.state.is_paused() {
+            return Ok(());
+        }
+        self.flush_all().await
+    }
+
+    /// Send all buffered messages, even if paused
+    async fn flush_all(&mut self) -> anyhow::Result<()> {
+        for message in std::mem::take(&mut self.paused_buffer) {
+            send_timed(self.config, &self.sender, message).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Pubkeys by the slot of their last update, evicting the least recently updated
+#[derive(Default)]
+struct SlotLru {
+    slots: HashMap<Pubkey, u64>,
+    by_slot: BTreeSet<(u64, Pubkey)>,
+}
+
+impl SlotLru {
+    /// Record an update of `pubkey`, returning the pubkeys evicted to stay within `capacity`
+    fn touch(&mut self, pubkey: Pubkey, slot: u64, capacity: usize) -> Vec<Pubkey> {
+        if let Some(previous) = self.slots.insert(pubkey, slot) {
+            self.by_slot.remove(&(previous, pubkey));
+        }
+        self.by_slot.insert((slot, pubkey));
+
+        let mut evicted = vec![];
+        while self.slots.len() > capacity {
+            // never evict the account that was just updated, even if its slot is old
+            let oldest = match self.by_slot.iter().find(|(_, key)| *key != pubkey) {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            self.by_slot.remove(&oldest);
+            self.slots.remove(&oldest.1);
+            evicted.push(oldest.1);
+        }
+        evicted
+    }
+}
+
+/// Highest forwarded account slot per stream and commitment, see Forwarder::min_context_slots
+///
+/// Slot messages don't count: a node announces slots before it delivers their
+/// account writes, and a processed slot says nothing about a confirmed stream.
+#[derive(Clone, Default)]
+struct Watermarks(Vec<(StreamKind, CommitmentConfig, u64)>);
+
+impl Watermarks {
+    fn get(&self, stream: StreamKind, commitment: CommitmentConfig) -> Option<u64> {
+        self.0
+            .iter()
+            .find(|(s, c, _)| *s == stream && *c == commitment)
+            .map(|(_, _, slot)| *slot)
+    }
+
+    fn record(&mut self, stream: StreamKind, commitment: CommitmentConfig, slot: u64) {
+        match self
+            .0
+            .iter_mut()
+            .find(|(s, c, _)| *s == stream && *c == commitment)
+        {
+            Some(entry) => entry.2 = entry.2.max(slot),
+            None => self.0.push((stream, commitment, slot)),
+        }
+    }
+}
+
+fn is_behind_watermark(min_context_slot: Option<u64>, slot: u64) -> bool {
+    min_context_slot.map_or(false, |min_slot| slot < min_slot)
+}
+
+fn slot_message_slot(message: &Message) -> u64 {
+    match message {
+        Message::Slot(update) => update.slot(),
+        Message::SlotInfo(info) => info.slot,
+        _ => 0,
+    }
+}
+
+/// The receiving side of the message channel was dropped, nobody reads the feed anymore
+#[derive(Debug, thiserror::Error)]
+#[error("the message receiver was dropped")]
+struct ReceiverClosed;
+
+/// Send a message, recording how long that blocked in Metrics::channel_send_seconds
+///
+/// Sends on unbounded channels never block and are not recorded.
+async fn send_timed(
+    config: &Config,
+    sender: &async_channel::Sender<Message>,
+    message: Message,
+) -> Result<(), ReceiverClosed> {
+    if sender.capacity().is_none() {
+        return sender.send(message).await.map_err(|_| ReceiverClosed);
+    }
+    let start = config.clock.now();
+    sender.send(message).await.map_err(|_| ReceiverClosed)?;
+    config
+        .metrics
+        .channel_send_seconds
+        .observe(config.clock.now() - start);
+    Ok(())
+}
+
+/// Tracks how far processed slots run ahead of confirmed ones
+#[derive(Default)]
+struct CommitmentLag {
+    processed: u64,
+    confirmed: u64,
+    exceeded_since: Option<Instant>,
+}
+
+impl CommitmentLag {
+    /// Errors if the lag was above Config::max_commitment_lag for too long
+    fn check(&mut self, config: &Config, message: &Message) -> anyhow::Result<()> {
+        let max_lag = match config.max_commitment_lag {
+            Some(max_lag) => max_lag,
+            None => return Ok(()),
+        };
+        match message {
+            Message::Slot(update) => match **update {
+                SlotUpdate::CreatedBank { slot, .. }
+                | SlotUpdate::Completed { slot, .. }
+                | SlotUpdate::Frozen { slot, .. } => {
+                    self.processed = self.processed.max(slot);
+                }
+                SlotUpdate::OptimisticConfirmation { slot, .. } | SlotUpdate::Root { slot, .. } => {
+                    self.confirmed = self.confirmed.max(slot);
+                }
+                _ => {}
+            },
+            Message::SlotInfo(info) => {
+                // slotSubscribe only reports roots, which trail further behind
+                self.processed = self.processed.max(info.slot);
+                self.confirmed = self.confirmed.max(info.root);
+            }
+            _ => {}
+        }
+
+        // until the first confirmation there is nothing to compare against
+        let lag = if self.confirmed > 0 {
+            self.processed.saturating_sub(self.confirmed)
+        } else {
+            0
+        };
+        if lag <= max_lag {
+            self.exceeded_since = None;
+            return Ok(());
+        }
+        let now = config.clock.now();
+        let exceeded_since = *self.exceeded_since.get_or_insert(now);
+        if now - exceeded_since > config.commitment_lag_timeout {
+            anyhow::bail!(
+                "processed slot {} is {lag} slots ahead of confirmed slot {} for {}s",
+                self.processed,
+                self.confirmed,
+                (now - exceeded_since).as_secs()
+            );
+        }
+        Ok(())
+    }
+}
+
+type SignatureSubMap = StreamMap<
+    Signature,
+    jsonrpc_core_client::TypedSubscriptionStream<Response<RpcSignatureResult>>,
+>;
+
+/// Make the signature subscriptions match the watched signatures
+fn subscribe_signatures(
+    config: &Config,
+    client: &RpcSolPubSubClient,
+    state: &SourceState,
+    subs: &mut SignatureSubMap,
 ) -> anyhow::Result<()> {
-    let connect = ws::try_connect::<RpcSolPubSubClient>(&config.rpc_ws_url).map_err_anyhow()?;
-    let client = connect.await.map_err_anyhow()?;
+    let watched = state.watched_signatures.lock().unwrap().clone();
+    let unwatched = subs
+        .keys()
+        .filter(|signature| !watched.contains(signature))
+        .cloned()
+        .collect::<Vec<_>>();
+    for signature in unwatched {
+        subs.remove(&signature);
+    }
+    for signature in watched {
+        if subs.contains_key(&signature) {
+            continue;
+        }
+        let sub = client
+            .signature_subscribe(
+                signature.to_string(),
+                Some(RpcSignatureSubscribeConfig {
+                    commitment: Some(config.signature_commitment),
+                    enable_received_notification: Some(false),
+                }),
+            )
+            .map_err_anyhow()?;
+        subs.insert(signature, sub);
+    }
+    Ok(())
+}
+
+/// A random duration between zero and `max`
+fn random_duration(max: Duration) -> Duration {
+    use std::hash::BuildHasher;
+    // every RandomState has random keys, good enough for jitter
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// Like tokio::time::timeout, but with the config's clock
+async fn timeout<F: std::future::Future>(
+    config: &Config,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = config.clock.sleep(duration) => None,
+    }
+}
+
+/// Which program accounts count as OpenOrders accounts of the open orders authority
+///
+/// The default matches serum v3. Serum-compatible dexes with a different account
+/// size or layout can adjust it, see Config::open_orders_filter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpenOrdersFilter {
+    data_size: u64,
+    account_flags: u64,
+    owner_offset: usize,
+}
 
-    let account_info_config = RpcAccountInfoConfig {
-        encoding: Some(UiAccountEncoding::Base64),
-        commitment: Some(CommitmentConfig::processed()),
-        data_slice: None,
-        min_context_slot: None,
+impl Default for OpenOrdersFilter {
+    fn default() -> Self {
+        Self {
+            data_size: 3228,
+            // Initialized (1) + OpenOrders (4)
+            account_flags: 5,
+            owner_offset: 45,
+        }
+    }
+}
+
+impl OpenOrdersFilter {
+    /// Accounts must have exactly this size
+    pub fn data_size(mut self, data_size: u64) -> Self {
+        self.data_size = data_size;
+        self
+    }
+
+    /// The account flags u64 after the "serum" prefix must be exactly these bits
+    pub fn account_flags(mut self, account_flags: u64) -> Self {
+        self.account_flags = account_flags;
+        self
+    }
+
+    /// Offset of the owner pubkey in the account data, including the "serum" prefix
+    pub fn owner_offset(mut self, owner_offset: usize) -> Self {
+        self.owner_offset = owner_offset;
+        self
+    }
+
+    pub fn expected_data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// (offset, bytes) that the accounts with `authority` as owner match
+    pub fn memcmps(&self, authority: &Pubkey) -> Vec<(usize, Vec<u8>)> {
+        let mut header = b"serum".to_vec();
+        header.extend_from_slice(&self.account_flags.to_le_bytes());
+        vec![
+            (0, header),
+            (self.owner_offset, authority.to_bytes().to_vec()),
+        ]
+    }
+
+    pub fn rpc_filters(&self, authority: &Pubkey) -> Vec<RpcFilterType> {
+        std::iter::once(RpcFilterType::DataSize(self.data_size))
+            .chain(
+                self.memcmps(authority)
+                    .into_iter()
+                    // new_base58_encoded() does not work with old RPC nodes
+                    .map(|(offset, bytes)| {
+                        RpcFilterType::Memcmp(Memcmp::new_raw_bytes(offset, bytes))
+                    }),
+            )
+            .collect()
+    }
+}
+
+/// Turn an account subscription notification into the same form as program notifications
+fn oracle_response(pubkey: Pubkey, response: Response<UiAccount>) -> Response<RpcKeyedAccount> {
+    Response {
+        context: RpcResponseContext {
+            slot: response.context.slot,
+            api_version: None,
+        },
+        value: RpcKeyedAccount {
+            pubkey: pubkey.to_string(),
+            account: response.value,
+        },
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn feed_data(
+    config: &Config,
+    state: &SourceState,
+    role: ConnectionRole,
+    session: &mut EndpointSession,
+    watermarks: &mut Watermarks,
+    connection: Connection,
+    setup_start: Instant,
+    oracle_selection: &OracleSelection,
+    sender: async_channel::Sender<Message>,
+) -> anyhow::Result<Option<Connection>> {
+    let client = connection.client;
+    let subscription_ids = connection.subscription_ids;
+    // for logs about closed streams
+    let stream_closed = |what: &'static str, method: &str, targets: &[String], newest_slot: u64| {
+        warn!(
+            "{what} stream closed, subscription {}, endpoint {}, newest slot {newest_slot}",
+            subscription_ids.lock().unwrap().describe(method, targets),
+            config.rpc_ws_url
+        );
+        emit(
+            config,
+            SourceEvent::StreamClosed {
+                url: config.rpc_ws_url.clone(),
+                stream: what,
+            },
+        );
     };
+    state.tracked_pubkeys.lock().unwrap().clear();
+    let min_context_slots = watermarks.clone();
+    let mut forwarder = Forwarder {
+        config,
+        state,
+        role,
+        session,
+        sender,
+        paused_buffer: vec![],
+        decode_stats: DecodeStats::new(config.clock.now()),
+        pending_setup: None,
+        newest_oracle_slot: None,
+        newest_slot: 0,
+        oracle_last_forwarded: HashMap::new(),
+        open_orders_lru: SlotLru::default(),
+        coalesced: HashMap::new(),
+        min_context_slots: min_context_slots.clone(),
+        watermarks,
+    };
+
+    let account_info_config =
+        |stream: StreamKind,
+         commitment: CommitmentConfig,
+         data_slice: Option<UiDataSliceConfig>| {
+            RpcAccountInfoConfig {
+                encoding: Some(config.encoding),
+                commitment: Some(commitment),
+                data_slice,
+                min_context_slot: min_context_slots.get(stream, commitment),
+            }
+        };
     let all_accounts_config = RpcProgramAccountsConfig {
-        filters: None,
+        filters: config.mango_program_filters(),
         with_context: Some(true),
-        account_config: account_info_config.clone(),
+        account_config: account_info_config(
+            StreamKind::Mango,
+            config.mango_commitment(),
+            config.mango_data_slice,
+        ),
     };
     let open_orders_accounts_config = RpcProgramAccountsConfig {
-        // filter for only OpenOrders with v4 authority
-        filters: Some(vec![
-            RpcFilterType::DataSize(3228), // open orders size
-            // "serum" + u64 that is Initialized (1) + OpenOrders (4)
-            RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
-                // new_base58_encoded() does not work with old RPC nodes
-                0,
-                [0x73, 0x65, 0x72, 0x75, 0x6d, 5, 0, 0, 0, 0, 0, 0, 0].to_vec(),
-            )),
-            RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
-                45,
-                config.open_orders_authority.to_bytes().to_vec(),
-            )),
-        ]),
+        filters: Some(
+            config
+                .open_orders_filter
+                .rpc_filters(&config.open_orders_authority),
+        ),
         with_context: Some(true),
-        account_config: account_info_config.clone(),
+        account_config: account_info_config(
+            StreamKind::OpenOrders,
+            config.open_orders_commitment(),
+            config.open_orders_data_slice,
+        ),
+    };
+    // Without the program subscription, a stream that never yields stands in
+    let _unused_mango_sender;
+    // The mango program subscription tries the encodings of encoding_preference
+    // in order, moving on when the rpc node rejects one.
+    let mango_encoding_at = |index: usize| {
+        config
+            .encoding_preference
+            .get(index)
+            .copied()
+            .unwrap_or(config.encoding)
+    };
+    let mut mango_encoding_index = 0;
+    let mut mango_encoding_selected = false;
+    let subscribe_mango = |commitment: CommitmentConfig, encoding: UiAccountEncoding| {
+        client
+            .program_subscribe(
+                mango_v4::id().to_string(),
+                Some(RpcProgramAccountsConfig {
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(encoding),
+                        ..account_info_config(
+                            StreamKind::Mango,
+                            commitment,
+                            config.mango_data_slice,
+                        )
+                    },
+                    ..all_accounts_config.clone()
+                }),
+            )
+            .map_err_anyhow()
+    };
+    let mut mango_sub = if config.mango_program_subscription {
+        subscribe_mango(config.mango_commitment(), mango_encoding_at(0))?
+    } else {
+        let (sender, receiver) = jsonrpc_core::futures::channel::mpsc::unbounded();
+        _unused_mango_sender = sender;
+        jsonrpc_core_client::TypedSubscriptionStream::new(receiver, "foo")
     };
-    let mut mango_sub = client
-        .program_subscribe(
-            mango_v4::id().to_string(),
-            Some(all_accounts_config.clone()),
-        )
-        .map_err_anyhow()?;
 
-    let mut mango_oracles_sub_map = StreamMap::new();
-    for oracle in mango_oracles.into_iter() {
-        mango_oracles_sub_map.insert(
-            oracle,
+    let mut watch_accounts_sub_map = StreamMap::new();
+    let mut watch_commitments = HashMap::new();
+    for spec in config.watch_accounts.iter() {
+        let commitment = spec.commitment.unwrap_or_else(|| config.mango_commitment());
+        watch_commitments.insert(spec.pubkey, commitment);
+        let spec_config = RpcAccountInfoConfig {
+            encoding: Some(spec.encoding.unwrap_or(config.encoding)),
+            data_slice: spec.data_slice,
+            ..account_info_config(StreamKind::Mango, commitment, None)
+        };
+        watch_accounts_sub_map.insert(
+            spec.pubkey,
             client
-                .account_subscribe(
-                    oracle.to_string(),
-                    Some(RpcAccountInfoConfig {
-                        encoding: Some(UiAccountEncoding::Base64),
-                        commitment: Some(CommitmentConfig::processed()),
-                        data_slice: None,
-                        min_context_slot: None,
-                    }),
-                )
+                .account_subscribe(spec.pubkey.to_string(), Some(spec_config))
                 .map_err_anyhow()?,
         );
     }
 
+    // Oracle subscriptions that fail don't stop the other streams: they are
+    // retried every oracle_retry_interval while running with the rest.
+    // SourceHandle::add_oracle() and remove_oracle() change them while running.
+    let subscribe_oracle = |oracle: Pubkey| {
+        client.account_subscribe(
+            oracle.to_string(),
+            Some(account_info_config(
+                StreamKind::Oracle,
+                config.oracle_commitment(),
+                None,
+            )),
+        )
+    };
+    let mut mango_oracles_sub_map = StreamMap::new();
+    let mut failed_oracles = vec![];
+    let mut oracles_changed = state.oracles_changed_receiver.clone();
+    let mango_oracles = oracle_selection.select(&state.oracles.lock().unwrap());
+    for oracle in mango_oracles.into_iter() {
+        match subscribe_oracle(oracle) {
+            Ok(sub) => {
+                mango_oracles_sub_map.insert(oracle, sub);
+            }
+            Err(err) => {
+                warn!("could not subscribe to oracle {oracle}: {err}");
+                failed_oracles.push(oracle);
+            }
+        }
+    }
+    if !failed_oracles.is_empty() {
+        warn_unsubscribed_oracles(&failed_oracles);
+    }
+    let mut oracle_retry = Interval::new(config.clock.clone(), config.oracle_retry_interval);
+    let coalescing = !config.account_coalesce_interval.is_zero();
+    // a zero period panics, the branch is disabled then anyway
+    let coalesce_period = config
+        .account_coalesce_interval
+        .max(Duration::from_millis(1));
+    let mut coalesce_flush = Interval::new(config.clock.clone(), coalesce_period);
+
     let mut serum3_oo_sub_map = StreamMap::new();
     for serum_program in config.serum_programs.iter() {
         serum3_oo_sub_map.insert(
@@ -109,103 +2360,1543 @@ async fn feed_data(
         );
     }
 
-    let mut slot_sub = client.slots_updates_subscribe().map_err_anyhow()?;
+    let serum_program_ids = config
+        .serum_programs
+        .iter()
+        .map(|pk| pk.to_string())
+        .collect::<Vec<_>>();
+    let slot_method = match config.slot_subscription_kind {
+        SlotSubscriptionKind::SlotsUpdates => "slotsUpdatesSubscribe",
+        SlotSubscriptionKind::Slot => "slotSubscribe",
+    };
+    let mut slot_sub = match config.slot_subscription_kind {
+        SlotSubscriptionKind::SlotsUpdates => client
+            .slots_updates_subscribe()
+            .map_err_anyhow()?
+            .map(|update| update.map(Message::Slot))
+            .boxed(),
+        SlotSubscriptionKind::Slot => client
+            .slot_subscribe()
+            .map_err_anyhow()?
+            .map(|info| info.map(|info| Message::SlotInfo(Arc::new(info))))
+            .boxed(),
+    };
+
+    let mut signature_sub_map = SignatureSubMap::new();
+    let mut signatures_changed = state.signatures_changed_receiver.clone();
+    let mut resumed = state.resumed_receiver.clone();
+    let watches_signatures = role == ConnectionRole::Main;
+    if watches_signatures {
+        subscribe_signatures(config, &client, state, &mut signature_sub_map)?;
+    }
+
+    let subscriptions = 1
+        + config.mango_program_subscription as usize
+        + signature_sub_map.len()
+        + watch_accounts_sub_map.len()
+        + mango_oracles_sub_map.len()
+        + config.serum_programs.len();
+    forwarder.pending_setup = Some((setup_start, subscriptions));
+
+    // send the messages that were already received, when stopping this connection
+    macro_rules! drain_received {
+        () => {
+            async {
+                while let Some(Some(Ok(message))) = slot_sub.next().now_or_never() {
+                    forwarder.slot(message).await?;
+                }
+                while let Some(Some(Ok(response))) = mango_sub.next().now_or_never() {
+                    forwarder.rpc_account(response, mango_commitment).await?;
+                }
+                while let Some(Some((pubkey, Ok(response)))) =
+                    watch_accounts_sub_map.next().now_or_never()
+                {
+                    forwarder
+                        .rpc_account(
+                            oracle_response(pubkey, response),
+                            watch_commitments[&pubkey],
+                        )
+                        .await?;
+                }
+                while let Some(Some((pubkey, Ok(response)))) =
+                    mango_oracles_sub_map.next().now_or_never()
+                {
+                    forwarder
+                        .oracle_account(oracle_response(pubkey, response))
+                        .await?;
+                }
+                while let Some(Some((_, Ok(response)))) = serum3_oo_sub_map.next().now_or_never() {
+                    forwarder.open_orders_account(response).await?;
+                }
+                forwarder.flush_coalesced().await?;
+                forwarder.flush_all().await?;
+                anyhow::Ok(())
+            }
+        };
+    }
+
+    // jittered, so that many clients don't reconnect at the same time
+    let mut connection_age = match config.max_connection_age {
+        Some(max_age) => config
+            .clock
+            .sleep(max_age.saturating_sub(random_duration(config.max_connection_age_jitter))),
+        None => future::pending().boxed(),
+    };
+    // the next connection, while it connects after the max age
+    let mut replacement = future::pending().boxed();
+
+    let mut commitment_lag = CommitmentLag::default();
+    // for detecting a processed program subscription that doesn't deliver
+    let mut mango_commitment = config.mango_commitment();
+    let mut last_mango_account = config.clock.now();
+    let mut slots_since_mango_account = 0u64;
+    // the confirmed subscription replacing a stalled processed one, which keeps
+    // running until the replacement delivered
+    let mut stall_sub = None;
+    let mut stall_fallback_failed = false;
+    let mut shutdown = state.shutdown_receiver.clone();
+    if state.is_shut_down() {
+        return Ok(None);
+    }
+
+    // (program, config, whether it's open orders) to fetch for the backfill snapshot
+    let mut backfill_requests = vec![];
+    if config.backfill_rpc_http_url.is_some() {
+        if config.mango_program_subscription {
+            backfill_requests.push((mango_v4::id(), all_accounts_config.clone(), false));
+        }
+        for serum_program in config.serum_programs.iter() {
+            backfill_requests.push((*serum_program, open_orders_accounts_config.clone(), true));
+        }
+    }
+    let mut backfill = future::pending().boxed();
+    // restarted by messages from the node only, local wakeups like the coalesce
+    // flush or the oracle retry must not keep an idle connection alive
+    let idle_deadline = || config.clock.sleep(config.ws_idle_timeout);
+    let mut idle = idle_deadline();
 
     loop {
-        tokio::select! {
-            message = mango_sub.next() => {
+        // only once the subscriptions are live, so no write is missed in between
+        if !backfill_requests.is_empty() && forwarder.pending_setup.is_none() {
+            let url = config.backfill_rpc_http_url.clone().unwrap_or_default();
+            backfill = fetch_backfill(url, std::mem::take(&mut backfill_requests)).boxed();
+        }
+
+        if let Some(stall_timeout) = config.processed_stall_timeout {
+            let stalled = config.mango_program_subscription
+                && mango_commitment == CommitmentConfig::processed()
+                && stall_sub.is_none()
+                && !stall_fallback_failed
+                && slots_since_mango_account > 0
+                && config.clock.now() - last_mango_account > stall_timeout;
+            if stalled {
+                warn!(
+                    "no mango accounts at processed for {}s while slots advance, resubscribing at confirmed",
+                    stall_timeout.as_secs()
+                );
+                match subscribe_mango(
+                    CommitmentConfig::confirmed(),
+                    mango_encoding_at(mango_encoding_index),
+                ) {
+                    Ok(sub) => stall_sub = Some(sub),
+                    Err(err) => {
+                        warn!("could not resubscribe at confirmed, staying at processed: {err:?}");
+                        stall_fallback_failed = true;
+                    }
+                }
+            }
+        }
+
+        if config.prioritize_slot_updates {
+            while let Some(message) = slot_sub.next().now_or_never() {
+                idle = idle_deadline();
                 if let Some(data) = message {
-                    let response = data.map_err_anyhow()?;
-                    sender.send(Message::Account(AccountUpdate::from_rpc(response)?)).await.expect("sending must succeed");
+                    slots_since_mango_account += 1;
+                    let message = data.map_err_anyhow()?;
+                    config
+                        .metrics
+                        .slot_stream
+                        .record(slot_message_slot(&message));
+                    commitment_lag.check(config, &message)?;
+                    forwarder.slot(message).await?;
                 } else {
-                    warn!("mango stream closed");
-                    return Ok(());
+                    stream_closed("slot update", slot_method, &[], forwarder.newest_slot);
+                    return Ok(None);
+                }
+            }
+        }
+
+        tokio::select! {
+            message = mango_sub.next() => {
+                idle = idle_deadline();
+                match message {
+                    Some(Ok(response)) => {
+                        if !mango_encoding_selected {
+                            mango_encoding_selected = true;
+                            info!(
+                                "mango program subscription uses encoding {:?}",
+                                mango_encoding_at(mango_encoding_index)
+                            );
+                        }
+                        last_mango_account = config.clock.now();
+                        slots_since_mango_account = 0;
+                        config.metrics.mango_stream.record(response.context.slot);
+                        forwarder.rpc_account(response, mango_commitment).await?;
+                    }
+                    Some(Err(RpcError::JsonRpcError(err)))
+                        if !mango_encoding_selected
+                            && mango_encoding_index + 1 < config.encoding_preference.len() =>
+                    {
+                        warn!(
+                            "mango program subscription with encoding {:?} was rejected: {err:?}",
+                            mango_encoding_at(mango_encoding_index)
+                        );
+                        mango_encoding_index += 1;
+                        mango_sub = subscribe_mango(
+                            mango_commitment,
+                            mango_encoding_at(mango_encoding_index),
+                        )?;
+                    }
+                    Some(Err(err)) => anyhow::bail!("mango stream error: {err:?}"),
+                    None => {
+                        stream_closed("mango", "programSubscribe", &[mango_v4::id().to_string()], forwarder.newest_slot);
+                        return Ok(None);
+                    }
                 }
             },
-            message = mango_oracles_sub_map.next() => {
-                if let Some(data) = message {
-                    let response = data.1.map_err_anyhow()?;
-                    let response = solana_client::rpc_response::Response{ context: RpcResponseContext{ slot: response.context.slot, api_version: None }, value: RpcKeyedAccount{ pubkey: data.0.to_string(), account:  response.value} } ;
-                    sender.send(Message::Account(AccountUpdate::from_rpc(response)?)).await.expect("sending must succeed");
-                } else {
-                    warn!("oracle stream closed");
-                    return Ok(());
+            message = async { stall_sub.as_mut().unwrap().next().await }, if stall_sub.is_some() => {
+                idle = idle_deadline();
+                match message {
+                    Some(Ok(response)) => {
+                        info!("mango program subscription switched to confirmed");
+                        config
+                            .metrics
+                            .processed_stall_fallbacks
+                            .fetch_add(1, Ordering::Relaxed);
+                        emit(config, SourceEvent::ProcessedStallFallback { url: config.rpc_ws_url.clone() });
+                        mango_sub = stall_sub.take().unwrap();
+                        mango_commitment = CommitmentConfig::confirmed();
+                        last_mango_account = config.clock.now();
+                        slots_since_mango_account = 0;
+                        config.metrics.mango_stream.record(response.context.slot);
+                        forwarder.rpc_account(response, mango_commitment).await?;
+                    }
+                    Some(Err(err)) => {
+                        warn!("mango program subscription at confirmed failed, staying at processed: {err:?}");
+                        stall_sub = None;
+                        stall_fallback_failed = true;
+                    }
+                    None => {
+                        warn!("mango program subscription at confirmed ended, staying at processed");
+                        stall_sub = None;
+                        stall_fallback_failed = true;
+                    }
+                }
+            },
+            message = watch_accounts_sub_map.next(), if !watch_accounts_sub_map.is_empty() => {
+                idle = idle_deadline();
+                if let Some((pubkey, data)) = message {
+                    let response = oracle_response(pubkey, data.map_err_anyhow()?);
+                    config.metrics.mango_stream.record(response.context.slot);
+                    forwarder.rpc_account(response, watch_commitments[&pubkey]).await?;
+                }
+            },
+            message = mango_oracles_sub_map.next(), if !mango_oracles_sub_map.is_empty() => {
+                idle = idle_deadline();
+                match message {
+                    Some((oracle, Ok(response))) => {
+                        config.metrics.oracle_stream.record(response.context.slot);
+                        forwarder.oracle_account(oracle_response(oracle, response)).await?;
+                    }
+                    Some((oracle, Err(err))) => {
+                        warn!("oracle {oracle} subscription failed: {err}");
+                        mango_oracles_sub_map.remove(&oracle);
+                        failed_oracles.push(oracle);
+                        warn_unsubscribed_oracles(&failed_oracles);
+                    }
+                    None => {
+                        stream_closed("oracle", "accountSubscribe", &[], forwarder.newest_slot);
+                        return Ok(None);
+                    }
+                }
+            },
+            message = signature_sub_map.next(), if !signature_sub_map.is_empty() => {
+                idle = idle_deadline();
+                match message {
+                    Some((signature, Ok(response))) => {
+                        // subscriptions end after the processed notification
+                        if let RpcSignatureResult::ProcessedSignature(result) = response.value {
+                            signature_sub_map.remove(&signature);
+                            state.watched_signatures.lock().unwrap().remove(&signature);
+                            forwarder
+                                .send(Message::Signature {
+                                    signature,
+                                    slot: response.context.slot,
+                                    err: result.err,
+                                })
+                                .await?;
+                        }
+                    }
+                    Some((signature, Err(err))) => {
+                        warn!("signature {signature} subscription failed: {err}");
+                        signature_sub_map.remove(&signature);
+                        state.watched_signatures.lock().unwrap().remove(&signature);
+                    }
+                    None => {}
+                }
+            },
+            _ = signatures_changed.changed(), if watches_signatures => {
+                subscribe_signatures(config, &client, state, &mut signature_sub_map)?;
+            },
+            _ = oracles_changed.changed() => {
+                let wanted = oracle_selection.select(&state.oracles.lock().unwrap());
+                let removed = mango_oracles_sub_map
+                    .keys()
+                    .filter(|oracle| !wanted.contains(*oracle))
+                    .copied()
+                    .collect::<Vec<_>>();
+                for oracle in removed {
+                    info!("unsubscribing from oracle {oracle}");
+                    mango_oracles_sub_map.remove(&oracle);
+                    forwarder.oracle_last_forwarded.remove(&oracle);
+                    state.tracked_pubkeys.lock().unwrap().remove(&oracle);
+                }
+                failed_oracles.retain(|oracle| wanted.contains(oracle));
+                for oracle in wanted {
+                    if mango_oracles_sub_map.contains_key(&oracle) || failed_oracles.contains(&oracle) {
+                        continue;
+                    }
+                    info!("subscribing to oracle {oracle}");
+                    match subscribe_oracle(oracle) {
+                        Ok(sub) => {
+                            mango_oracles_sub_map.insert(oracle, sub);
+                        }
+                        Err(err) => {
+                            warn!("could not subscribe to oracle {oracle}: {err}");
+                            failed_oracles.push(oracle);
+                        }
+                    }
+                }
+            },
+            _ = coalesce_flush.tick(), if coalescing => {
+                forwarder.flush_coalesced().await?;
+            },
+            _ = oracle_retry.tick(), if !failed_oracles.is_empty() => {
+                info!("retrying {} oracle subscriptions", failed_oracles.len());
+                let mut still_failed = vec![];
+                for oracle in failed_oracles.drain(..) {
+                    match subscribe_oracle(oracle) {
+                        Ok(sub) => {
+                            mango_oracles_sub_map.insert(oracle, sub);
+                        }
+                        Err(err) => {
+                            warn!("could not subscribe to oracle {oracle}: {err}");
+                            still_failed.push(oracle);
+                        }
+                    }
                 }
+                failed_oracles = still_failed;
             },
             message = serum3_oo_sub_map.next() => {
+                idle = idle_deadline();
                 if let Some(data) = message {
                     let response = data.1.map_err_anyhow()?;
-                    sender.send(Message::Account(AccountUpdate::from_rpc(response)?)).await.expect("sending must succeed");
+                    config.metrics.open_orders_stream.record(response.context.slot);
+                    forwarder.open_orders_account(response).await?;
                 } else {
-                    warn!("serum stream closed");
-                    return Ok(());
+                    stream_closed("serum", "programSubscribe", &serum_program_ids, forwarder.newest_slot);
+                    return Ok(None);
                 }
             },
             message = slot_sub.next() => {
+                idle = idle_deadline();
                 if let Some(data) = message {
-                    sender.send(Message::Slot(data.map_err_anyhow()?)).await.expect("sending must succeed");
+                    slots_since_mango_account += 1;
+                    let message = data.map_err_anyhow()?;
+                    config.metrics.slot_stream.record(slot_message_slot(&message));
+                    commitment_lag.check(config, &message)?;
+                    forwarder.slot(message).await?;
                 } else {
-                    warn!("slot update stream closed");
-                    return Ok(());
+                    stream_closed("slot update", slot_method, &[], forwarder.newest_slot);
+                    return Ok(None);
+                }
+            },
+            _ = shutdown.changed() => {
+                info!("websocket source shutting down, sending already received messages");
+                match timeout(config, config.shutdown_drain_timeout, drain_received!()).await {
+                    Some(result) => result?,
+                    None => warn!("timeout while sending already received messages on shutdown"),
+                }
+                return Ok(None);
+            },
+            _ = &mut connection_age => {
+                info!(
+                    "websocket connection to {} reached its max age, reconnecting",
+                    config.rpc_ws_url
+                );
+                // this connection keeps forwarding while the next one connects
+                connection_age = future::pending().boxed();
+                replacement = connect(config).boxed();
+            },
+            next = &mut replacement => {
+                replacement = future::pending().boxed();
+                match next {
+                    Ok(next) => {
+                        match timeout(config, config.shutdown_drain_timeout, drain_received!()).await {
+                            Some(result) => result?,
+                            None => warn!("timeout while sending already received messages on reconnect"),
+                        }
+                        return Ok(Some(next));
+                    }
+                    Err(err) => {
+                        warn!(
+                            "connecting after max connection age failed, keeping the current connection: {err:?}"
+                        );
+                        connection_age = config.clock.sleep(config.connect_retry_delay);
+                    }
+                }
+            },
+            result = &mut backfill => {
+                backfill = future::pending().boxed();
+                match result {
+                    Ok(responses) => {
+                        for (response, open_orders) in responses {
+                            forwarder.snapshot(response, open_orders).await?;
+                        }
+                    }
+                    Err(err) => warn!("could not backfill accounts: {err:?}"),
                 }
             },
-            _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                warn!("websocket timeout");
-                return Ok(())
+            // keeps the buffer if paused again since
+            _ = resumed.changed(), if !forwarder.paused_buffer.is_empty() => {
+                forwarder.flush_paused().await?;
+            },
+            _ = &mut idle => {
+                warn!(
+                    "websocket timeout, endpoint {}, newest slot {}",
+                    config.rpc_ws_url, forwarder.newest_slot
+                );
+                emit(config, SourceEvent::Timeout { url: config.rpc_ws_url.clone() });
+                return Ok(None)
             }
         }
     }
 }
 
-pub fn start(config: Config, mango_oracles: Vec<Pubkey>, sender: async_channel::Sender<Message>) {
-    tokio::spawn(async move {
-        // if the websocket disconnects, we get no data in a while etc, reconnect and try again
+/// getProgramAccounts for each (program, config, open orders) request, see
+/// Config::backfill_rpc_http_url
+async fn fetch_backfill(
+    rpc_http_url: String,
+    requests: Vec<(Pubkey, RpcProgramAccountsConfig, bool)>,
+) -> anyhow::Result<Vec<(Response<Vec<RpcKeyedAccount>>, bool)>> {
+    let rpc_client = http::connect_with_options::<AccountsScanClient>(&rpc_http_url, true)
+        .await
+        .map_err_anyhow()?;
+    let mut responses = vec![];
+    for (program, program_config, open_orders) in requests {
+        let response = rpc_client
+            .get_program_accounts(program.to_string(), Some(program_config))
+            .await
+            .map_err_anyhow()
+            .with_context(|| format!("getProgramAccounts for {program}"))?;
+        match response {
+            OptionalContext::Context(response) => responses.push((response, open_orders)),
+            OptionalContext::NoContext(_) => {
+                anyhow::bail!("getProgramAccounts for {program} did not return a context")
+            }
+        }
+    }
+    Ok(responses)
+}
+
+fn warn_unsubscribed_oracles(oracles: &[Pubkey]) {
+    let list = oracles
+        .iter()
+        .map(|pk| pk.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    warn!(
+        "running without {} oracle subscriptions, will retry: {list}",
+        oracles.len()
+    );
+}
+
+/// Close the channel for a connection that gave up
+///
+/// Oracle shards share the channel with the main connection, which keeps
+/// running when a shard stops: only the main connection closes it.
+fn close_if_main(role: ConnectionRole, sender: &async_channel::Sender<Message>) {
+    if role == ConnectionRole::Main {
+        sender.close();
+    } else {
+        warn!("oracle shard gave up, its oracles are no longer updated");
+    }
+}
+
+/// Runs feed_data and reconnects when it stops
+async fn run(
+    config: Config,
+    state: Arc<SourceState>,
+    role: ConnectionRole,
+    oracle_selection: OracleSelection,
+    sender: async_channel::Sender<Message>,
+) {
+    let urls = std::iter::once(config.rpc_ws_url.clone())
+        .chain(config.fallback_ws_urls.iter().cloned())
+        .collect::<Vec<_>>();
+    {
+        let mut stats = state.endpoint_stats.lock().unwrap();
+        for url in urls.iter() {
+            stats.entry(url.clone()).or_default();
+        }
+    }
+    let mut url_index = 0;
+    // when EndpointSelection::Priority moved away from rpc_ws_url
+    let mut left_primary_at = None;
+    // the url to use after `current`, `long_lived` if the last connection stayed up long
+    let mut next_endpoint = |current: usize, failed: &HashSet<usize>, long_lived: bool| {
+        let next = match config.endpoint_selection {
+            EndpointSelection::BestScore => {
+                best_endpoint(&state.endpoint_stats.lock().unwrap(), &urls, failed)
+            }
+            EndpointSelection::Priority { primary_cooldown } => {
+                let cooled_down = left_primary_at.map_or(false, |left: Instant| {
+                    config.clock.now() - left >= primary_cooldown
+                });
+                priority_endpoint(current, urls.len(), failed, long_lived || cooled_down)
+            }
+        };
+        if next == 0 {
+            left_primary_at = None;
+        } else if left_primary_at.is_none() {
+            left_primary_at = Some(config.clock.now());
+        }
+        next
+    };
+    // urls that failed to connect since the last successful connect
+    let mut failed_urls = HashSet::new();
+    // urls that refused authorization, never tried again
+    let mut unauthorized_urls = HashSet::new();
+    // the longest rate limit backoff requested in this connect cycle
+    let mut rate_limit_wait = Duration::ZERO;
+    let mut reconnects = 0u32;
+    // disconnects since the last connection that stayed up, for the reconnect backoff
+    let mut disconnects = 0u32;
+    // set when feed_data already connected again, see Config::max_connection_age
+    let mut next_connection = None;
+    // highest slot seen on any connection, for Message::Disconnected
+    let mut last_slot = 0;
+    // account slots forwarded by the earlier connections, see Forwarder::min_context_slots
+    let mut watermarks = Watermarks::default();
+    // if the websocket disconnects, we get no data in a while etc, reconnect and try again
+    loop {
+        // nobody would read the messages of a new connection
+        if sender.is_closed() {
+            info!("message receiver was dropped, stopping websocket source");
+            return;
+        }
+        info!(
+            "connecting to solana websocket streams at {} (endpoint {} of {})",
+            urls[url_index],
+            url_index + 1,
+            urls.len()
+        );
+        let endpoint_config = Config {
+            rpc_ws_url: urls[url_index].clone(),
+            ..config.clone()
+        };
+        let setup_start = config.clock.now();
+        let connection = match next_connection.take() {
+            Some(connection) => Ok(connection),
+            None => connect(&endpoint_config).await,
+        };
+        match connection {
+            Ok(connection) => {
+                failed_urls.clear();
+                failed_urls.extend(unauthorized_urls.iter().copied());
+                rate_limit_wait = Duration::ZERO;
+                emit(
+                    &config,
+                    SourceEvent::Connected {
+                        url: endpoint_config.rpc_ws_url.clone(),
+                    },
+                );
+                let mut session = EndpointSession::default();
+                let out = feed_data(
+                    &endpoint_config,
+                    &state,
+                    role,
+                    &mut session,
+                    &mut watermarks,
+                    connection,
+                    setup_start,
+                    &oracle_selection,
+                    sender.clone(),
+                )
+                .await;
+                let connected = config.clock.now() - setup_start;
+                last_slot = last_slot.max(session.newest_slot);
+                let long_lived =
+                    connected >= config.reconnect_backoff_reset_after && session.received() > 0;
+                state
+                    .endpoint_stats
+                    .lock()
+                    .unwrap()
+                    .entry(endpoint_config.rpc_ws_url.clone())
+                    .or_default()
+                    .record_session(connected, &session);
+                // planned reconnects already connected to the same url
+                if !matches!(out, Ok(Some(_))) {
+                    url_index = next_endpoint(url_index, &failed_urls, long_lived);
+                }
+                let clean_close = out.is_ok();
+                match out {
+                    Err(err) if err.is::<ReceiverClosed>() => {
+                        info!("message receiver was dropped, stopping websocket source");
+                        return;
+                    }
+                    Ok(next) => next_connection = next,
+                    Err(err) => {
+                        warn!("websocket stream error: {err}");
+                        emit(
+                            &config,
+                            SourceEvent::StreamError {
+                                url: endpoint_config.rpc_ws_url.clone(),
+                                error: format!("{err:?}"),
+                            },
+                        );
+                    }
+                }
+
+                // planned reconnects go ahead right away, without a gap
+                if next_connection.is_none() && !state.is_shut_down() {
+                    let disconnected = Message::Disconnected {
+                        last_slot,
+                        url: endpoint_config.rpc_ws_url.clone(),
+                        role,
+                    };
+                    if send_timed(&config, &sender, disconnected).await.is_err() {
+                        info!("message receiver was dropped, stopping websocket source");
+                        return;
+                    }
+                    if long_lived {
+                        disconnects = 0;
+                    }
+                    let backoff = reconnect_backoff(
+                        config.reconnect_backoff_base,
+                        config.reconnect_backoff_max,
+                        disconnects,
+                        clean_close,
+                    );
+                    disconnects = disconnects.saturating_add(1);
+                    debug!(
+                        "reconnecting in {}ms after {disconnects} disconnects in a row",
+                        backoff.as_millis()
+                    );
+                    let mut shutdown = state.shutdown_receiver.clone();
+                    tokio::select! {
+                        _ = config.clock.sleep(backoff) => {},
+                        _ = shutdown.changed() => {},
+                    }
+                }
+            }
+            Err(err) => {
+                match err.downcast_ref::<HandshakeError>() {
+                    Some(HandshakeError::Unauthorized { status, .. }) => {
+                        error!(
+                            "websocket endpoint {} refused authorization (HTTP {status}), check the api key; not connecting to it again",
+                            endpoint_config.rpc_ws_url
+                        );
+                        unauthorized_urls.insert(url_index);
+                    }
+                    Some(HandshakeError::RateLimited { retry_after, .. }) => {
+                        let wait = retry_after.unwrap_or(config.rate_limit_backoff);
+                        warn!(
+                            "websocket endpoint {} is rate limiting connections (HTTP 429), backing off for {}s",
+                            endpoint_config.rpc_ws_url,
+                            wait.as_secs()
+                        );
+                        rate_limit_wait = rate_limit_wait.max(wait);
+                    }
+                    Some(HandshakeError::Rejected { status, .. }) => {
+                        warn!(
+                            "websocket upgrade to {} failed with HTTP {status}",
+                            endpoint_config.rpc_ws_url
+                        );
+                    }
+                    None => warn!("websocket stream error: {err:?}"),
+                }
+                emit(
+                    &config,
+                    SourceEvent::ConnectFailed {
+                        url: endpoint_config.rpc_ws_url.clone(),
+                        error: format!("{err:?}"),
+                    },
+                );
+                state
+                    .endpoint_stats
+                    .lock()
+                    .unwrap()
+                    .entry(endpoint_config.rpc_ws_url.clone())
+                    .or_default()
+                    .record_failure((config.clock.now() - setup_start).max(config.connect_timeout));
+                if unauthorized_urls.len() >= urls.len() {
+                    error!("all websocket endpoints refused authorization, giving up");
+                    emit(&config, SourceEvent::GaveUp { reconnects, role });
+                    close_if_main(role, &sender);
+                    return;
+                }
+                failed_urls.insert(url_index);
+                if failed_urls.len() >= urls.len() {
+                    failed_urls.clear();
+                    failed_urls.extend(unauthorized_urls.iter().copied());
+                    error!(
+                        "connecting failed for all {} websocket endpoints: {}",
+                        urls.len(),
+                        urls.join(", ")
+                    );
+                    emit(
+                        &config,
+                        SourceEvent::AllEndpointsFailed { urls: urls.clone() },
+                    );
+                    let backoff = match &config.on_all_endpoints_failed {
+                        AllEndpointsFailed::Backoff(backoff) => *backoff,
+                        AllEndpointsFailed::Callback { backoff, callback } => {
+                            callback(&urls);
+                            *backoff
+                        }
+                    };
+                    let backoff = backoff.max(std::mem::take(&mut rate_limit_wait));
+                    config.clock.sleep(backoff).await;
+                }
+                url_index = next_endpoint(url_index, &failed_urls, false);
+            }
+        }
+        if state.is_shut_down() {
+            info!("websocket source stopped");
+            return;
+        }
+        // planned reconnects don't count against max_lifetime_reconnects
+        if next_connection.is_some() {
+            continue;
+        }
+
+        if let Some(max_reconnects) = config.max_lifetime_reconnects {
+            if reconnects >= max_reconnects {
+                error!("websocket streams stopped after {reconnects} reconnects, giving up");
+                emit(&config, SourceEvent::GaveUp { reconnects, role });
+                close_if_main(role, &sender);
+                return;
+            }
+        }
+        reconnects += 1;
+    }
+}
+
+/// How many slots back account data is remembered for cross-checking
+const CROSS_CHECK_SLOTS: u64 = 150;
+
+/// Compares the account updates of two sources in dual source mode
+#[derive(Default)]
+struct CrossCheck {
+    /// source index and data hash of the latest update seen per pubkey and slot
+    seen: HashMap<(Pubkey, u64), (usize, u64)>,
+    newest_slot: u64,
+    /// both sources report watched signatures, only forward the first
+    signatures_seen: HashSet<Signature>,
+    /// both sources send every slot update, only forward the first per slot and status
+    slot_updates_seen: HashSet<(u64, std::mem::Discriminant<SlotUpdate>)>,
+    slot_infos_seen: HashSet<u64>,
+    /// per source, the last_slot of its Disconnected until its next message
+    disconnected: [Option<u64>; 2],
+    /// whether a Snapshot was forwarded since both sources were last disconnected
+    snapshot_forwarded: bool,
+}
+
+impl CrossCheck {
+    fn data_hash(update: &AccountUpdate) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        update.account.lamports().hash(&mut hasher);
+        update.account.owner().hash(&mut hasher);
+        update.account.data().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Insert the `key` of a message at `slot`, false if it was seen; forgets much older slots
+    fn insert_slot<T: Eq + Hash>(
+        seen: &mut HashSet<T>,
+        key: T,
+        slot: u64,
+        slot_of: impl Fn(&T) -> u64,
+    ) -> bool {
+        if seen.len() > 10000 {
+            let min_slot = slot.saturating_sub(CROSS_CHECK_SLOTS);
+            seen.retain(|key| slot_of(key) >= min_slot);
+        }
+        seen.insert(key)
+    }
+
+    /// Returns the message to forward, if any
+    fn process(&mut self, source: usize, message: Message) -> Option<Message> {
+        if let Message::Disconnected {
+            last_slot,
+            url,
+            role,
+        } = message
+        {
+            self.disconnected[source] = Some(last_slot);
+            // nothing was missed while the other source still streams
+            let other_last_slot = self.disconnected[1 - source]?;
+            self.snapshot_forwarded = false;
+            return Some(Message::Disconnected {
+                last_slot: last_slot.max(other_last_slot),
+                url,
+                role,
+            });
+        }
+        self.disconnected[source] = None;
+
+        let update = match message {
+            Message::Account(update) => update,
+            Message::Signature { signature, .. } => {
+                if self.signatures_seen.len() > 10000 {
+                    self.signatures_seen.clear();
+                }
+                return self.signatures_seen.insert(signature).then_some(message);
+            }
+            Message::Slot(update) => {
+                let slot = update.slot();
+                let key = (slot, std::mem::discriminant(&*update));
+                return Self::insert_slot(&mut self.slot_updates_seen, key, slot, |key| key.0)
+                    .then_some(Message::Slot(update));
+            }
+            Message::SlotInfo(info) => {
+                let slot = info.slot;
+                return Self::insert_slot(&mut self.slot_infos_seen, slot, slot, |slot| *slot)
+                    .then_some(Message::SlotInfo(info));
+            }
+            // one snapshot covers the gap, the other source's is redundant
+            Message::Snapshot(snapshot) => {
+                if self.snapshot_forwarded {
+                    return None;
+                }
+                self.snapshot_forwarded = true;
+                return Some(Message::Snapshot(snapshot));
+            }
+            other => return Some(other),
+        };
+        let key = (update.pubkey, update.slot);
+        let hash = Self::data_hash(&update);
+        match self.seen.get(&key) {
+            Some(&(seen_source, seen_hash)) if seen_source != source => {
+                if seen_hash == hash {
+                    return None;
+                }
+                warn!(
+                    "rpc nodes disagree about account {} at slot {}",
+                    update.pubkey, update.slot
+                );
+                return Some(Message::Disagreement {
+                    pubkey: update.pubkey,
+                    slot: update.slot,
+                });
+            }
+            _ => {}
+        }
+
+        self.seen.insert(key, (source, hash));
+        if update.slot > self.newest_slot {
+            self.newest_slot = update.slot;
+            let min_slot = self.newest_slot.saturating_sub(CROSS_CHECK_SLOTS);
+            if self.seen.len() > 10000 {
+                self.seen.retain(|(_, slot), _| *slot >= min_slot);
+            }
+        }
+        Some(Message::Account(update))
+    }
+}
+
+async fn cross_check(
+    config: Config,
+    state: Arc<SourceState>,
+    primary: async_channel::Receiver<Message>,
+    secondary: async_channel::Receiver<Message>,
+    sender: async_channel::Sender<Message>,
+) {
+    let mut check = CrossCheck::default();
+    let mut open = [true, true];
+    loop {
+        let (source, message) = tokio::select! {
+            message = primary.recv(), if open[0] => (0, message),
+            message = secondary.recv(), if open[1] => (1, message),
+            else => break,
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(_) => {
+                open[source] = false;
+                continue;
+            }
+        };
+        let checked = match message {
+            // check the updates one by one and forward the accepted ones as a batch again
+            Message::AccountBatch(updates) => {
+                let mut batch = vec![];
+                let mut checked = vec![];
+                for update in updates {
+                    match check.process(source, Message::Account(update)) {
+                        Some(Message::Account(update)) => batch.push(update),
+                        Some(other) => checked.push(other),
+                        None => {}
+                    }
+                }
+                if !batch.is_empty() {
+                    checked.insert(0, Message::AccountBatch(batch));
+                }
+                checked
+            }
+            message => check.process(source, message).into_iter().collect(),
+        };
+        for message in checked {
+            if send_timed(&config, &sender, message).await.is_err() {
+                info!("message receiver was dropped, stopping the dual source cross-check");
+                return;
+            }
+        }
+    }
+    // both sources gave up, see Config::max_lifetime_reconnects
+    if !state.is_shut_down() {
+        sender.close();
+    }
+}
+
+/// Spawns the websocket feed
+///
+/// The returned handle's task resolves when the main connection stops, which is when
+/// - max_lifetime_reconnects is exceeded, or every endpoint refused authorization;
+///   in both cases the channel gets closed, for all senders
+/// - SourceHandle::shutdown() was called and the buffered messages were drained
+/// - the receiver was dropped
+///
+/// Failing to connect to all endpoints is not one of them, that only backs off, see
+/// Config::on_all_endpoints_failed. In dual source mode the task resolves once both
+/// sources stopped or the receiver was dropped.
+///
+/// Ordering: messages from one subscription arrive on `sender` in the order the
+/// rpc node sent them, for example mango account writes or slot updates. Messages
+/// from different subscriptions are interleaved arbitrarily, an oracle write can
+/// be forwarded before a mango account write the node sent earlier. In dual
+/// source mode and across reconnects there is no ordering guarantee at all.
+pub fn start(
+    config: Config,
+    mango_oracles: Vec<Pubkey>,
+    sender: async_channel::Sender<Message>,
+) -> SourceHandle {
+    if config.has_commitment_mismatch() {
+        warn!(
+            "websocket streams use different commitments (mango: {:?}, oracles: {:?}, open orders: {:?}), \
+             oracle prices and account states may be from different slots",
+            config.mango_commitment().commitment,
+            config.oracle_commitment().commitment,
+            config.open_orders_commitment().commitment,
+        );
+    }
+    if config.max_connection_age.is_some() && config.backfill_rpc_http_url.is_none() {
+        warn!(
+            "max_connection_age is set without backfill_rpc_http_url, updates sent while \
+             the next connection subscribes are missed on every planned reconnect"
+        );
+    }
+
+    let state = Arc::new(SourceState::new(mango_oracles.clone()));
+    let (task, connections) = match config.dual_source_ws_url.clone() {
+        None => spawn_connections(config, state.clone(), mango_oracles, sender),
+        Some(secondary_url) => {
+            let secondary_config = Config {
+                rpc_ws_url: secondary_url,
+                fallback_ws_urls: vec![],
+                dual_source_ws_url: None,
+                ..config.clone()
+            };
+            let (primary_sender, primary_receiver) = async_channel::unbounded();
+            let (secondary_sender, secondary_receiver) = async_channel::unbounded();
+            let (_, primary_connections) = spawn_connections(
+                config.clone(),
+                state.clone(),
+                mango_oracles.clone(),
+                primary_sender,
+            );
+            let (_, secondary_connections) = spawn_connections(
+                secondary_config,
+                state.clone(),
+                mango_oracles,
+                secondary_sender,
+            );
+            let task = tokio::spawn(cross_check(
+                config,
+                state.clone(),
+                primary_receiver,
+                secondary_receiver,
+                sender,
+            ));
+            (task, primary_connections + secondary_connections)
+        }
+    };
+    SourceHandle {
+        task,
+        state,
+        connections,
+    }
+}
+
+impl AccountSource for Config {
+    /// Like start(), without the extra controls of the SourceHandle
+    fn start(
+        self,
+        mango_oracles: Vec<Pubkey>,
+        sender: async_channel::Sender<Message>,
+    ) -> tokio::task::JoinHandle<()> {
+        start(self, mango_oracles, sender).task
+    }
+}
+
+/// Delay before reconnecting, after `disconnects` earlier disconnects in a row
+///
+/// Doubles from `base` with every disconnect up to `max`, see Config::reconnect_backoff_base.
+pub(crate) fn reconnect_backoff(
+    base: Duration,
+    max: Duration,
+    disconnects: u32,
+    clean_close: bool,
+) -> Duration {
+    let backoff = base.saturating_mul(1 << disconnects.min(16)).min(max);
+    if clean_close {
+        backoff / 2
+    } else {
+        backoff
+    }
+}
+
+/// Split the oracles so no connection exceeds Config::max_subscriptions_per_connection
+///
+/// The first shard goes to the main connection, next to its other subscriptions.
+fn shard_oracles(config: &Config, mut oracles: Vec<Pubkey>) -> Vec<Vec<Pubkey>> {
+    let max_subscriptions = match config.max_subscriptions_per_connection {
+        Some(max_subscriptions) => max_subscriptions,
+        None => return vec![oracles],
+    };
+    let main_subscriptions = 1
+        + config.mango_program_subscription as usize
+        + config.watch_accounts.len()
+        + config.serum_programs.len();
+    let main_oracles = max_subscriptions
+        .saturating_sub(main_subscriptions)
+        .min(oracles.len());
+    // each shard also has a slot subscription
+    let shard_size = max_subscriptions.saturating_sub(1).max(1);
+    let rest = oracles.split_off(main_oracles);
+    std::iter::once(oracles)
+        .chain(rest.chunks(shard_size).map(|shard| shard.to_vec()))
+        .collect()
+}
+
+/// Spawn run() for the main connection and any oracle shards
+///
+/// Returns the main connection's task and the number of connections.
+fn spawn_connections(
+    config: Config,
+    state: Arc<SourceState>,
+    mango_oracles: Vec<Pubkey>,
+    sender: async_channel::Sender<Message>,
+) -> (tokio::task::JoinHandle<()>, usize) {
+    // the main connection takes the first shard and every oracle added later
+    let shards = shard_oracles(&config, mango_oracles)
+        .into_iter()
+        .skip(1)
+        .map(|shard| Arc::new(shard.into_iter().collect::<HashSet<_>>()))
+        .collect::<Vec<_>>();
+    let sharded_oracles = Arc::new(
+        shards
+            .iter()
+            .flat_map(|shard| shard.iter().copied())
+            .collect::<HashSet<_>>(),
+    );
+    let mut connections = 1;
+    for shard in shards {
+        let shard_config = Config {
+            mango_program_subscription: false,
+            serum_programs: vec![],
+            watch_accounts: vec![],
+            ..config.clone()
+        };
+        tokio::spawn(run(
+            shard_config,
+            state.clone(),
+            ConnectionRole::OracleShard,
+            OracleSelection::Only(shard),
+            sender.clone(),
+        ));
+        connections += 1;
+    }
+    if connections > 1 {
+        info!(
+            "sharding oracle subscriptions for {} across {connections} connections",
+            config.rpc_ws_url
+        );
+    }
+    let task = tokio::spawn(run(
+        config,
+        state,
+        ConnectionRole::Main,
+        OracleSelection::AllExcept(sharded_oracles),
+        sender,
+    ));
+    (task, connections)
+}
+
+/// Fan-out of the message stream, where every subscriber sees every message
+///
+/// Unlike with the async_channel of start(), subscribers don't compete for
+/// messages. A subscriber that falls more than the broadcast capacity behind
+/// misses the oldest messages: its next recv() skips ahead and the number of
+/// missed messages is added to Metrics::broadcast_lagged. Those subscribers
+/// should treat their state as incomplete, for example by reloading a snapshot.
+#[derive(Clone)]
+pub struct Broadcast {
+    /// None once the source stopped, so that receivers see the channel closing
+    sender: Arc<Mutex<Option<tokio::sync::broadcast::Sender<Message>>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Broadcast {
+    /// Receive all messages sent from now on
+    pub fn subscribe(&self) -> BroadcastReceiver {
+        let receiver = match self.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.subscribe(),
+            // the source stopped, hand out a closed receiver
+            None => tokio::sync::broadcast::channel(1).1,
+        };
+        BroadcastReceiver {
+            receiver,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+pub struct BroadcastReceiver {
+    receiver: tokio::sync::broadcast::Receiver<Message>,
+    metrics: Arc<Metrics>,
+}
+
+impl BroadcastReceiver {
+    /// The next message, None once the source stopped
+    pub async fn recv(&mut self) -> Option<Message> {
+        use tokio::sync::broadcast::error::RecvError;
         loop {
-            info!("connecting to solana websocket streams");
-            let out = feed_data(&config, mango_oracles.clone(), sender.clone());
-            let result = out.await;
-            if let Err(err) = result {
-                warn!("websocket stream error: {err}");
+            match self.receiver.recv().await {
+                Ok(message) => return Some(message),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("broadcast subscriber lagged behind, missed {missed} messages");
+                    self.metrics
+                        .broadcast_lagged
+                        .fetch_add(missed, Ordering::Relaxed);
+                }
+                Err(RecvError::Closed) => return None,
             }
         }
+    }
+}
+
+/// Like start(), but fanning out messages to any number of subscribers
+///
+/// Messages are dropped while there are no subscribers.
+pub fn start_broadcast(
+    config: Config,
+    mango_oracles: Vec<Pubkey>,
+    capacity: usize,
+) -> (SourceHandle, Broadcast) {
+    let (broadcast_sender, _) = tokio::sync::broadcast::channel(capacity);
+    let broadcast = Broadcast {
+        sender: Arc::new(Mutex::new(Some(broadcast_sender.clone()))),
+        metrics: config.metrics.clone(),
+    };
+    let (sender, receiver) = async_channel::bounded(capacity);
+    let handle = start(config, mango_oracles, sender);
+    let shared_sender = broadcast.sender.clone();
+    tokio::spawn(async move {
+        while let Ok(message) = receiver.recv().await {
+            // fails only if there are no subscribers
+            let _ = broadcast_sender.send(message);
+        }
+        shared_sender.lock().unwrap().take();
     });
+    (handle, broadcast)
 }
 
-pub async fn get_next_create_bank_slot(
+/// Wait for the first slot message reaching `status`, returning its slot
+///
+/// Processed matches CreatedBank updates and the slot of SlotInfo messages, Rooted
+/// matches Root updates and the root of SlotInfo messages. Confirmed only matches
+/// OptimisticConfirmation updates, which need a slotsUpdates subscription.
+/// Other messages are dropped. Errors when the channel closes or after `timeout`
+/// has passed on `clock`.
+pub async fn get_next_slot(
     receiver: async_channel::Receiver<Message>,
     timeout: Duration,
+    status: SlotStatus,
+    clock: &dyn Clock,
 ) -> anyhow::Result<u64> {
-    let start = std::time::Instant::now();
+    let what = match &status {
+        SlotStatus::Processed => "processed",
+        SlotStatus::Confirmed => "confirmed",
+        SlotStatus::Rooted => "rooted",
+    };
+    let start = clock.now();
     loop {
-        let elapsed = start.elapsed();
-        if elapsed > timeout {
+        let elapsed = clock.now() - start;
+        if elapsed >= timeout {
             anyhow::bail!(
-                "did not receive a slot from the websocket connection in {}s",
+                "did not receive a {what} slot from the websocket connection in {}s",
                 timeout.as_secs()
             );
         }
         let remaining_timeout = timeout - elapsed;
 
-        let msg = match tokio::time::timeout(remaining_timeout, receiver.recv()).await {
-            // timeout
-            Err(_) => continue,
-            // channel close
-            Ok(Err(err)) => {
-                return Err(err).context("while waiting for first slot from websocket connection");
-            }
-            // success
-            Ok(Ok(msg)) => msg,
+        let msg = tokio::select! {
+            msg = receiver.recv() => match msg {
+                Ok(msg) => msg,
+                // channel close
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("while waiting for a {what} slot from websocket connection")
+                    });
+                }
+            },
+            _ = clock.sleep(remaining_timeout) => continue,
         };
 
-        match msg {
-            Message::Slot(slot_update) => {
-                if let solana_client::rpc_response::SlotUpdate::CreatedBank { slot, .. } =
-                    *slot_update
-                {
-                    return Ok(slot);
-                }
-            }
-            _ => {}
+        let slot = match (&msg, &status) {
+            (Message::Slot(slot_update), _) => match (&**slot_update, &status) {
+                (SlotUpdate::CreatedBank { slot, .. }, SlotStatus::Processed)
+                | (SlotUpdate::OptimisticConfirmation { slot, .. }, SlotStatus::Confirmed)
+                | (SlotUpdate::Root { slot, .. }, SlotStatus::Rooted) => Some(*slot),
+                _ => None,
+            },
+            (Message::SlotInfo(slot_info), SlotStatus::Processed) => Some(slot_info.slot),
+            (Message::SlotInfo(slot_info), SlotStatus::Rooted) => Some(slot_info.root),
+            _ => None,
+        };
+        if let Some(slot) = slot {
+            return Ok(slot);
+        }
+    }
+}
+
+pub async fn get_next_create_bank_slot(
+    receiver: async_channel::Receiver<Message>,
+    timeout: Duration,
+) -> anyhow::Result<u64> {
+    get_next_slot(receiver, timeout, SlotStatus::Processed, &RealClock).await
+}
+
+pub async fn get_next_confirmed_slot(
+    receiver: async_channel::Receiver<Message>,
+    timeout: Duration,
+) -> anyhow::Result<u64> {
+    get_next_slot(receiver, timeout, SlotStatus::Confirmed, &RealClock).await
+}
+
+/// Like get_next_create_bank_slot, for startup logic that must only see finalized state
+pub async fn get_next_rooted_slot(
+    receiver: async_channel::Receiver<Message>,
+    timeout: Duration,
+) -> anyhow::Result<u64> {
+    get_next_slot(receiver, timeout, SlotStatus::Rooted, &RealClock).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::__private::bytemuck;
+    use serum_dex::state::{AccountFlag, OpenOrders};
+
+    /// Whether account data passes the filters, the way the rpc node checks them
+    fn filters_match(filters: &[RpcFilterType], data: &[u8]) -> bool {
+        filters.iter().all(|filter| match filter {
+            RpcFilterType::DataSize(size) => data.len() as u64 == *size,
+            RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(data),
+            _ => false,
+        })
+    }
+
+    /// A serum OpenOrders account as stored on chain, with head and tail padding
+    fn open_orders_data(owner: &Pubkey) -> Vec<u8> {
+        let mut open_orders: OpenOrders = bytemuck::Zeroable::zeroed();
+        open_orders.account_flags =
+            AccountFlag::Initialized as u64 | AccountFlag::OpenOrders as u64;
+        open_orders.owner = bytemuck::cast(owner.to_bytes());
+        let mut data = b"serum".to_vec();
+        data.extend_from_slice(bytemuck::bytes_of(&open_orders));
+        data.extend_from_slice(b"padding");
+        data
+    }
+
+    #[test]
+    fn test_open_orders_filters() {
+        let authority = Pubkey::new_unique();
+        let filters = OpenOrdersFilter::default().rpc_filters(&authority);
+
+        let data = open_orders_data(&authority);
+        assert_eq!(data.len(), 3228);
+        assert!(filters_match(&filters, &data));
+
+        let other = open_orders_data(&Pubkey::new_unique());
+        assert!(!filters_match(&filters, &other));
+
+        // not an initialized open orders account
+        let mut uninitialized = data.clone();
+        uninitialized[5] = 0;
+        assert!(!filters_match(&filters, &uninitialized));
+    }
+
+    #[test]
+    fn test_mango_program_filters() {
+        let group = Pubkey::new_unique();
+        let account_data = |group: &Pubkey| {
+            let mut data = vec![7u8; 8];
+            data.extend_from_slice(&group.to_bytes());
+            data.extend_from_slice(&[0u8; 64]);
+            data
+        };
+        assert!(Config::default().mango_program_filters().is_none());
+
+        let filters = Config {
+            group: Some(group),
+            ..Default::default()
+        }
+        .mango_program_filters()
+        .unwrap();
+        assert!(filters_match(&filters, &account_data(&group)));
+        assert!(!filters_match(
+            &filters,
+            &account_data(&Pubkey::new_unique())
+        ));
+    }
+
+    #[test]
+    fn test_from_file_error_line() {
+        let from_toml = |content: String| {
+            let path =
+                std::env::temp_dir().join(format!("websocket-{}.toml", Pubkey::new_unique()));
+            std::fs::write(&path, content).unwrap();
+            let err = Config::from_file(&path).unwrap_err().to_string();
+            std::fs::remove_file(&path).unwrap();
+            err
+        };
+        let valid = Pubkey::new_unique();
+
+        // "abc" is also part of the url, the error points at the oracle entry
+        let err = from_toml(format!(
+            "rpc_ws_url = \"ws://abc\"\nopen_orders_authority = \"{valid}\"\noracles = [\n  \"{valid}\",\n  \"abc\",\n]\n"
+        ));
+        assert!(
+            err.contains(", line 5: bad pubkey \"abc\" in oracles"),
+            "{err}"
+        );
+
+        let err = from_toml(format!(
+            "rpc_ws_url = \"ws://localhost\"\nserum_programs = [\"{valid}\"]\nopen_orders_authority = \"\"\n"
+        ));
+        assert!(
+            err.contains(", line 3: bad pubkey \"\" in open_orders_authority"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_open_orders_filter_serum_defaults() {
+        use solana_client::rpc_filter::MemcmpEncodedBytes;
+        let authority = Pubkey::new_unique();
+        let filter = OpenOrdersFilter::default();
+        assert_eq!(filter.expected_data_size(), 3228);
+
+        let memcmps = filter.memcmps(&authority);
+        let serum_header = Memcmp::new(0, MemcmpEncodedBytes::Base58("AcUQf4PGf6fCHGwmpB".into()));
+        assert_eq!(memcmps[0].0, 0);
+        assert_eq!(&memcmps[0].1, &*serum_header.bytes().unwrap());
+        assert_eq!(memcmps[1], (45, authority.to_bytes().to_vec()));
+
+        let other_dex = filter
+            .data_size(3300)
+            .owner_offset(53)
+            .account_flags(1 | 4 | 8);
+        let memcmps = other_dex.memcmps(&authority);
+        assert_eq!(other_dex.expected_data_size(), 3300);
+        assert_eq!(&memcmps[0].1[5..], &13u64.to_le_bytes());
+        assert_eq!(memcmps[1].0, 53);
+    }
+
+    #[test]
+    fn test_handshake_error() {
+        use tokio_tungstenite::tungstenite::http::Response;
+        let response = |status: u16| Response::builder().status(status);
+        let url = "wss://rpc/key";
+
+        let unauthorized = HandshakeError::from_response(url, &response(401).body(()).unwrap());
+        assert!(matches!(
+            unauthorized,
+            HandshakeError::Unauthorized { status: 401, .. }
+        ));
+        assert!(HandshakeError::is_persistent(&unauthorized.into()));
+
+        let rate_limited = response(429)
+            .header(header::RETRY_AFTER, "7")
+            .body(())
+            .unwrap();
+        assert!(matches!(
+            HandshakeError::from_response(url, &rate_limited),
+            HandshakeError::RateLimited { retry_after: Some(d), .. } if d == Duration::from_secs(7)
+        ));
+
+        let unavailable = HandshakeError::from_response(url, &response(503).body(()).unwrap());
+        assert!(matches!(
+            unavailable,
+            HandshakeError::Rejected { status: 503, .. }
+        ));
+        let err = anyhow::Error::from(unavailable).context("connecting");
+        assert!(!HandshakeError::is_persistent(&err));
+        assert!(err.downcast_ref::<HandshakeError>().is_some());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let config = Config::default();
+        let backoff = |disconnects, clean_close| {
+            reconnect_backoff(
+                config.reconnect_backoff_base,
+                config.reconnect_backoff_max,
+                disconnects,
+                clean_close,
+            )
+        };
+        assert_eq!(backoff(0, false), Duration::from_millis(500));
+        assert_eq!(backoff(1, false), Duration::from_secs(1));
+        assert_eq!(backoff(3, false), Duration::from_secs(4));
+        assert_eq!(backoff(6, false), Duration::from_secs(30));
+        assert_eq!(backoff(u32::MAX, false), Duration::from_secs(30));
+        assert_eq!(backoff(1, true), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_shard_oracles() {
+        let oracles = (0..10).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let mut config = Config {
+            serum_programs: vec![Pubkey::new_unique()],
+            ..Config::default()
+        };
+        assert_eq!(
+            shard_oracles(&config, oracles.clone()),
+            vec![oracles.clone()]
+        );
+
+        // main: slots, mango, serum and 2 oracles; shards: slots and 4 oracles
+        config.max_subscriptions_per_connection = Some(5);
+        let shards = shard_oracles(&config, oracles.clone());
+        let sizes = shards.iter().map(|shard| shard.len()).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![2, 4, 4]);
+        assert_eq!(shards.concat(), oracles);
+
+        // the oracles fit
+        config.max_subscriptions_per_connection = Some(100);
+        assert_eq!(shard_oracles(&config, oracles.clone()).len(), 1);
+    }
+
+    #[test]
+    fn test_slot_lru() {
+        let keys = (0..4).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let mut lru = SlotLru::default();
+        assert!(lru.touch(keys[0], 10, 2).is_empty());
+        assert!(lru.touch(keys[1], 12, 2).is_empty());
+        // updating a tracked account doesn't evict
+        assert!(lru.touch(keys[0], 13, 2).is_empty());
+        // keys[1] now has the oldest update
+        assert_eq!(lru.touch(keys[2], 14, 2), vec![keys[1]]);
+        // an update with an old slot still gets tracked
+        assert_eq!(lru.touch(keys[3], 5, 2), vec![keys[0]]);
+        assert_eq!(lru.slots.len(), 2);
+        assert_eq!(lru.by_slot.len(), 2);
+        assert!(lru.slots.contains_key(&keys[2]));
+        assert!(lru.slots.contains_key(&keys[3]));
+    }
+
+    #[test]
+    fn test_best_endpoint() {
+        let urls = vec![
+            "ws://a".to_string(),
+            "ws://b".to_string(),
+            "ws://c".to_string(),
+        ];
+        let mut stats = HashMap::new();
+        let mut failed = HashSet::new();
+        // nothing known yet: the main url
+        assert_eq!(best_endpoint(&stats, &urls, &failed), 0);
+
+        let session = |accounts, lag| EndpointSession {
+            accounts,
+            lag_sum: lag,
+            lag_samples: 1,
+            ..EndpointSession::default()
+        };
+        let a = stats
+            .entry(urls[0].clone())
+            .or_insert_with(EndpointStats::default);
+        a.record_session(Duration::from_secs(100), &session(1000, 0));
+        // a good endpoint is preferred over an untried one
+        assert_eq!(best_endpoint(&stats, &urls, &failed), 0);
+
+        // lagging behind and dropping updates makes b worse than a
+        let b = stats
+            .entry(urls[1].clone())
+            .or_insert_with(EndpointStats::default);
+        b.record_session(Duration::from_secs(100), &session(1000, 64));
+        let scores = endpoint_scores(&stats, &urls);
+        assert_eq!(scores[0].score, 1.0);
+        assert!(scores[1].score < scores[0].score);
+        assert_eq!(scores[1].commitment_lag, 64.0);
+
+        // failing endpoints lose to unknown ones
+        for _ in 0..10 {
+            stats
+                .get_mut(&urls[0])
+                .unwrap()
+                .record_failure(Duration::from_secs(60));
         }
+        let scores = endpoint_scores(&stats, &urls);
+        assert_eq!(scores[0].consecutive_failures, 10);
+        assert!(scores[0].uptime < 0.5);
+        assert_eq!(best_endpoint(&stats, &urls, &failed), 2);
+
+        // urls that failed in this connect cycle are skipped
+        failed.insert(2);
+        assert_eq!(best_endpoint(&stats, &urls, &failed), 1);
+    }
+
+    #[test]
+    fn test_priority_endpoint() {
+        let mut failed = HashSet::new();
+        // every reconnect moves on, wrapping around
+        assert_eq!(priority_endpoint(0, 3, &failed, false), 1);
+        assert_eq!(priority_endpoint(1, 3, &failed, false), 2);
+        assert_eq!(priority_endpoint(2, 3, &failed, false), 0);
+        // after a long lived connection or the cooldown, back to the primary
+        assert_eq!(priority_endpoint(1, 3, &failed, true), 0);
+        assert_eq!(priority_endpoint(0, 1, &failed, false), 0);
+
+        // failed urls are skipped, also when going back to the primary
+        failed.insert(0);
+        failed.insert(2);
+        assert_eq!(priority_endpoint(1, 3, &failed, false), 1);
+        assert_eq!(priority_endpoint(1, 3, &failed, true), 1);
+        assert_eq!(priority_endpoint(2, 3, &failed, true), 1);
     }
 }
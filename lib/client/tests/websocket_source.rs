@@ -0,0 +1,1607 @@
+//! Tests for websocket_source against an in-process mock of the solana pubsub api
+
+use anchor_lang::Discriminator;
+use anchor_lang::__private::bytemuck;
+use fixed::types::I80F48;
+use futures::{FutureExt, SinkExt, StreamExt};
+use mango_v4::state::StubOracle;
+use mango_v4_client::account_update_stream::{ConnectionRole, Message};
+use mango_v4_client::chain_data::SlotStatus;
+use mango_v4_client::clock::MockClock;
+use mango_v4_client::websocket_source::{self, PauseMode, SourceEvent, StreamKind};
+use serde_json::{json, Value};
+use solana_account_decoder::{UiAccount, UiAccountEncoding, UiDataSliceConfig};
+use solana_client::rpc_response::{
+    ProcessedSignatureResult, Response, RpcKeyedAccount, RpcResponseContext, RpcSignatureResult,
+    SlotUpdate,
+};
+use solana_sdk::account::{AccountSharedData, ReadableAccount};
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::TransactionError;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+#[derive(Clone, Debug)]
+struct Subscription {
+    connection: usize,
+    id: u64,
+    /// like "programSubscribe"
+    method: String,
+    /// first parameter, the program or account pubkey if there is one
+    target: Option<String>,
+    /// second parameter, the subscription config
+    config: Value,
+}
+
+#[derive(Clone, Debug)]
+enum Command {
+    Notify {
+        method: &'static str,
+        target: Option<String>,
+        result: Value,
+    },
+    /// close one connection, or all if None
+    Disconnect(Option<usize>),
+}
+
+/// See MockPubsubServer::reject()
+struct Rejection {
+    remaining: usize,
+    matches: Box<dyn Fn(&Subscription) -> bool + Send>,
+}
+
+/// Speaks just enough of the solana pubsub protocol to accept subscriptions
+/// and push notifications to them.
+struct MockPubsubServer {
+    url: String,
+    commands: broadcast::Sender<Command>,
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+    rejections: Arc<Mutex<Vec<Rejection>>>,
+}
+
+impl MockPubsubServer {
+    async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (commands, _) = broadcast::channel(1000);
+        let subscriptions = Arc::new(Mutex::new(vec![]));
+        let rejections = Arc::new(Mutex::new(vec![]));
+
+        tokio::spawn({
+            let commands = commands.clone();
+            let subscriptions = subscriptions.clone();
+            let rejections = rejections.clone();
+            async move {
+                let mut connection = 0;
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    connection += 1;
+                    tokio::spawn(handle_connection(
+                        stream,
+                        connection,
+                        commands.subscribe(),
+                        subscriptions.clone(),
+                        rejections.clone(),
+                    ));
+                }
+            }
+        });
+
+        Self {
+            url,
+            commands,
+            subscriptions,
+            rejections,
+        }
+    }
+
+    /// Subscribe requests that reject() still answers with an error
+    fn rejections_pending(&self) -> usize {
+        self.rejections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|rejection| rejection.remaining)
+            .sum()
+    }
+
+    /// Answer the next `count` subscribe requests that `matches` with an error
+    fn reject(&self, count: usize, matches: impl Fn(&Subscription) -> bool + Send + 'static) {
+        self.rejections.lock().unwrap().push(Rejection {
+            remaining: count,
+            matches: Box::new(matches),
+        });
+    }
+
+    fn subscriptions(&self) -> Vec<Subscription> {
+        self.subscriptions.lock().unwrap().clone()
+    }
+
+    /// Wait until there are `count` active subscriptions, returning them
+    async fn wait_for_subscriptions(&self, count: usize) -> Vec<Subscription> {
+        wait_until(&format!("{count} subscriptions"), || {
+            self.subscriptions().len() >= count
+        })
+        .await;
+        self.subscriptions()
+    }
+
+    fn notify(&self, method: &'static str, target: Option<&Pubkey>, result: Value) {
+        self.commands
+            .send(Command::Notify {
+                method,
+                target: target.map(|pk| pk.to_string()),
+                result,
+            })
+            .unwrap();
+    }
+
+    fn notify_program_account(&self, program: &Pubkey, pubkey: &Pubkey, slot: u64, data: &[u8]) {
+        let result = Response {
+            context: RpcResponseContext {
+                slot,
+                api_version: None,
+            },
+            value: RpcKeyedAccount {
+                pubkey: pubkey.to_string(),
+                account: ui_account(pubkey, program, data),
+            },
+        };
+        self.notify(
+            "programSubscribe",
+            Some(program),
+            serde_json::to_value(result).unwrap(),
+        );
+    }
+
+    fn notify_account(&self, owner: &Pubkey, pubkey: &Pubkey, slot: u64, data: &[u8]) {
+        let result = Response {
+            context: RpcResponseContext {
+                slot,
+                api_version: None,
+            },
+            value: ui_account(pubkey, owner, data),
+        };
+        self.notify(
+            "accountSubscribe",
+            Some(pubkey),
+            serde_json::to_value(result).unwrap(),
+        );
+    }
+
+    fn notify_slot(&self, update: SlotUpdate) {
+        self.notify(
+            "slotsUpdatesSubscribe",
+            None,
+            serde_json::to_value(update).unwrap(),
+        );
+    }
+
+    fn notify_signature(&self, signature: &Signature, slot: u64, err: Option<TransactionError>) {
+        let result = Response {
+            context: RpcResponseContext {
+                slot,
+                api_version: None,
+            },
+            value: RpcSignatureResult::ProcessedSignature(ProcessedSignatureResult { err }),
+        };
+        self.commands
+            .send(Command::Notify {
+                method: "signatureSubscribe",
+                target: Some(signature.to_string()),
+                result: serde_json::to_value(result).unwrap(),
+            })
+            .unwrap();
+    }
+
+    fn disconnect_all(&self) {
+        self.commands.send(Command::Disconnect(None)).unwrap();
+    }
+
+    fn disconnect(&self, connection: usize) {
+        self.commands
+            .send(Command::Disconnect(Some(connection)))
+            .unwrap();
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    connection: usize,
+    mut commands: broadcast::Receiver<Command>,
+    subscriptions: Arc<Mutex<Vec<Subscription>>>,
+    rejections: Arc<Mutex<Vec<Rejection>>>,
+) {
+    let (mut write, mut read) = tokio_tungstenite::accept_async(stream)
+        .await
+        .unwrap()
+        .split();
+    let mut next_id = 1;
+    loop {
+        tokio::select! {
+            message = read.next() => {
+                let text = match message {
+                    Some(Ok(WsMessage::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                    _ => break,
+                };
+                let request: Value = serde_json::from_str(&text).unwrap();
+                let method = request["method"].as_str().unwrap().to_string();
+                let response = if method.ends_with("Unsubscribe") {
+                    let id = request["params"][0].as_u64();
+                    subscriptions
+                        .lock()
+                        .unwrap()
+                        .retain(|s| s.connection != connection || Some(s.id) != id);
+                    json!({"jsonrpc": "2.0", "result": true, "id": request["id"]})
+                } else {
+                    let subscription = Subscription {
+                        connection,
+                        id: next_id,
+                        method,
+                        target: request["params"][0].as_str().map(|s| s.to_string()),
+                        config: request["params"][1].clone(),
+                    };
+                    let rejected = rejections.lock().unwrap().iter_mut().any(|rejection| {
+                        let matches = rejection.remaining > 0 && (rejection.matches)(&subscription);
+                        if matches {
+                            rejection.remaining -= 1;
+                        }
+                        matches
+                    });
+                    if rejected {
+                        let error = json!({"code": -32602, "message": "rejected by the mock server"});
+                        json!({"jsonrpc": "2.0", "error": error, "id": request["id"]})
+                    } else {
+                        let id = subscription.id;
+                        next_id += 1;
+                        subscriptions.lock().unwrap().push(subscription);
+                        json!({"jsonrpc": "2.0", "result": id, "id": request["id"]})
+                    }
+                };
+                if write.send(WsMessage::Text(response.to_string())).await.is_err() {
+                    break;
+                }
+            },
+            command = commands.recv() => {
+                let (method, target, result) = match command {
+                    Ok(Command::Notify { method, target, result }) => (method, target, result),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Ok(Command::Disconnect(Some(other))) if other != connection => continue,
+                    Ok(Command::Disconnect(_)) | Err(_) => {
+                        let _ = write.close().await;
+                        break;
+                    }
+                };
+                let targets = subscriptions
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|s| {
+                        s.connection == connection
+                            && s.method == method
+                            && (target.is_none() || s.target == target)
+                    })
+                    .map(|s| s.id)
+                    .collect::<Vec<_>>();
+                for id in targets {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": method.replace("Subscribe", "Notification"),
+                        "params": {"result": result, "subscription": id},
+                    });
+                    if write.send(WsMessage::Text(notification.to_string())).await.is_err() {
+                        break;
+                    }
+                }
+            },
+        }
+    }
+    subscriptions
+        .lock()
+        .unwrap()
+        .retain(|s| s.connection != connection);
+}
+
+fn ui_account(pubkey: &Pubkey, owner: &Pubkey, data: &[u8]) -> UiAccount {
+    let mut account = AccountSharedData::new(1_000_000, data.len(), owner);
+    account.set_data_from_slice(data);
+    UiAccount::encode(pubkey, &account, UiAccountEncoding::Base64, None, None)
+}
+
+fn config(server: &MockPubsubServer, serum_program: Pubkey) -> websocket_source::Config {
+    websocket_source::Config {
+        rpc_ws_url: server.url.clone(),
+        serum_programs: vec![serum_program],
+        open_orders_authority: Pubkey::new_unique(),
+        ..Default::default()
+    }
+}
+
+/// Wait for `condition`, for things the source does in the background
+async fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timeout waiting for {what}");
+}
+
+async fn recv(receiver: &async_channel::Receiver<Message>) -> Message {
+    tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .expect("message within timeout")
+        .expect("channel open")
+}
+
+// mango program, one oracle, one serum program and slot updates
+const SUBSCRIPTION_COUNT: usize = 4;
+
+#[tokio::test]
+async fn test_forward_notifications() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let oracle = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    let config = config(&server, serum_program);
+    let metrics = config.metrics.clone();
+    websocket_source::start(config, vec![oracle], sender);
+
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    let mut methods = subscriptions
+        .iter()
+        .map(|s| s.method.as_str())
+        .collect::<Vec<_>>();
+    methods.sort();
+    assert_eq!(
+        methods,
+        vec![
+            "accountSubscribe",
+            "programSubscribe",
+            "programSubscribe",
+            "slotsUpdatesSubscribe"
+        ]
+    );
+
+    server.notify_slot(SlotUpdate::CreatedBank {
+        slot: 10,
+        parent: 9,
+        timestamp: 0,
+    });
+    match recv(&receiver).await {
+        Message::Slot(update) => match *update {
+            SlotUpdate::CreatedBank { slot, parent, .. } => {
+                assert_eq!((slot, parent), (10, 9));
+            }
+            _ => panic!("unexpected slot update"),
+        },
+        _ => panic!("expected slot message"),
+    }
+
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1, 2, 3]);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, mango_account);
+            assert_eq!(update.slot, 10);
+            assert_eq!(update.account.data(), &[1, 2, 3]);
+            assert!(update.is_mango(&mango_v4::id()));
+        }
+        _ => panic!("expected account message"),
+    }
+
+    let oracle_owner = Pubkey::new_unique();
+    server.notify_account(&oracle_owner, &oracle, 11, &[4, 5]);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, oracle);
+            assert_eq!(update.slot, 11);
+            assert_eq!(update.owner(), &oracle_owner);
+            assert_eq!(update.account.data(), &[4, 5]);
+        }
+        _ => panic!("expected account message"),
+    }
+
+    let open_orders = Pubkey::new_unique();
+    server.notify_program_account(&serum_program, &open_orders, 12, &[6]);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, open_orders);
+            assert_eq!(update.owner(), &serum_program);
+        }
+        _ => panic!("expected account message"),
+    }
+
+    server.notify_slot(SlotUpdate::CreatedBank {
+        slot: 15,
+        parent: 14,
+        timestamp: 0,
+    });
+    recv(&receiver).await;
+    for (kind, slot) in [
+        (StreamKind::Mango, 10),
+        (StreamKind::Oracle, 11),
+        (StreamKind::OpenOrders, 12),
+        (StreamKind::Slot, 15),
+    ] {
+        let stream = metrics.stream(kind);
+        assert_eq!(stream.last_slot.load(Ordering::Relaxed), slot, "{kind:?}");
+        assert!(stream.messages.load(Ordering::Relaxed) >= 1, "{kind:?}");
+        assert!(
+            stream.last_message_unix_ms.load(Ordering::Relaxed) > 0,
+            "{kind:?}"
+        );
+    }
+    assert_eq!(metrics.account_feed_lag(), 3);
+}
+
+#[tokio::test]
+async fn test_reconnect_after_close() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        config(&server, serum_program),
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(|s| s.connection == 1));
+
+    server.notify_slot(SlotUpdate::CreatedBank {
+        slot: 15,
+        parent: 14,
+        timestamp: 0,
+    });
+    server.notify_program_account(&mango_v4::id(), &Pubkey::new_unique(), 12, &[1]);
+    recv(&receiver).await;
+    recv(&receiver).await;
+
+    server.disconnect_all();
+    wait_until("the reconnect", || {
+        server.subscriptions().iter().any(|s| s.connection > 1)
+    })
+    .await;
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(|s| s.connection > 1));
+
+    // one gap marker with the highest slot seen, then data flows on the new connection
+    match recv(&receiver).await {
+        Message::Disconnected {
+            last_slot,
+            url,
+            role,
+        } => {
+            assert_eq!(last_slot, 15);
+            assert_eq!(url, server.url);
+            assert_eq!(role, ConnectionRole::Main);
+        }
+        _ => panic!("expected disconnected message"),
+    }
+    server.notify_slot(SlotUpdate::Root {
+        slot: 20,
+        timestamp: 0,
+    });
+    match recv(&receiver).await {
+        Message::Slot(update) => assert_eq!(update.slot(), 20),
+        _ => panic!("expected slot message"),
+    }
+}
+
+#[tokio::test]
+async fn test_idle_timeout_with_mock_clock() {
+    let server = MockPubsubServer::start().await;
+    let clock = Arc::new(MockClock::default());
+    let (sender, _receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            clock: clock.clone(),
+            // reconnect without waiting on the mock clock
+            reconnect_backoff_base: Duration::ZERO,
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    // the idle watchdog is the only sleep once subscribed
+    wait_until("1 sleep", || clock.pending_sleeps() == 1).await;
+
+    clock.advance(Duration::from_secs(59));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+
+    clock.advance(Duration::from_secs(1));
+    wait_until("the reconnect", || {
+        server.subscriptions().iter().any(|s| s.connection > 1)
+    })
+    .await;
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(|s| s.connection > 1));
+}
+
+#[tokio::test]
+async fn test_idle_timeout_while_coalescing() {
+    let server = MockPubsubServer::start().await;
+    let clock = Arc::new(MockClock::default());
+    let (sender, _receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            clock: clock.clone(),
+            reconnect_backoff_base: Duration::ZERO,
+            account_coalesce_interval: Duration::from_secs(1),
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    // the idle watchdog and the next coalesce flush
+    // the flushes every second don't count as activity
+    for _ in 0..59 {
+        wait_until("2 sleeps", || clock.pending_sleeps() == 2).await;
+        clock.advance(Duration::from_secs(1));
+    }
+    wait_until("2 sleeps", || clock.pending_sleeps() == 2).await;
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+
+    clock.advance(Duration::from_secs(1));
+    wait_until("the reconnect", || {
+        server.subscriptions().iter().any(|s| s.connection > 1)
+    })
+    .await;
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(|s| s.connection > 1));
+}
+
+#[tokio::test]
+async fn test_per_stream_ordering() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        config(&server, Pubkey::new_unique()),
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    // interleave slot updates and mango account writes, several per slot
+    let accounts = (0..10).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    let mut expected_accounts = vec![];
+    let mut expected_slots = vec![];
+    for slot in 1..=20u64 {
+        server.notify_slot(SlotUpdate::CreatedBank {
+            slot,
+            parent: slot - 1,
+            timestamp: 0,
+        });
+        expected_slots.push(slot);
+        for (i, account) in accounts.iter().enumerate().take(slot as usize % 4) {
+            server.notify_program_account(&mango_v4::id(), account, slot, &[i as u8]);
+            expected_accounts.push((*account, slot, i as u8));
+        }
+    }
+
+    let mut accounts_received = vec![];
+    let mut slots_received = vec![];
+    while accounts_received.len() < expected_accounts.len()
+        || slots_received.len() < expected_slots.len()
+    {
+        match recv(&receiver).await {
+            Message::Account(update) => {
+                accounts_received.push((update.pubkey, update.slot, update.account.data()[0]))
+            }
+            Message::Slot(update) => slots_received.push(update.slot()),
+            _ => panic!("unexpected message"),
+        }
+    }
+    assert_eq!(accounts_received, expected_accounts);
+    assert_eq!(slots_received, expected_slots);
+}
+
+/// A token-2022 mint with a MintCloseAuthority extension
+fn token_2022_mint_data(close_authority: &Pubkey) -> Vec<u8> {
+    // base mint: no mint authority, supply, decimals, initialized, no freeze authority
+    let mut data = vec![0u8; 4 + 32];
+    data.extend_from_slice(&1_000_000u64.to_le_bytes());
+    data.push(6);
+    data.push(1);
+    data.extend_from_slice(&[0u8; 4 + 32]);
+    assert_eq!(data.len(), 82);
+    // padded to the token account size, then the account type and extensions
+    data.resize(165, 0);
+    data.push(1); // AccountType::Mint
+    data.extend_from_slice(&3u16.to_le_bytes()); // ExtensionType::MintCloseAuthority
+    data.extend_from_slice(&32u16.to_le_bytes());
+    data.extend_from_slice(close_authority.as_ref());
+    data
+}
+
+#[tokio::test]
+async fn test_watch_token_2022_mint() {
+    let server = MockPubsubServer::start().await;
+    let token_2022_program =
+        Pubkey::from_str("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb").unwrap();
+    let mint = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            watch_accounts: vec![mint.into()],
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 1).await;
+
+    let data = token_2022_mint_data(&Pubkey::new_unique());
+    server.notify_account(&token_2022_program, &mint, 30, &data);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, mint);
+            assert_eq!(update.owner(), &token_2022_program);
+            assert_eq!(update.account.data(), data.as_slice());
+        }
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_add_remove_oracle() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let handle = websocket_source::start(config(&server, Pubkey::new_unique()), vec![], sender);
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT - 1).await;
+
+    let oracle = Pubkey::new_unique();
+    handle.add_oracle(oracle);
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(|s| s.connection == 1));
+    assert!(subscriptions
+        .iter()
+        .any(|s| s.method == "accountSubscribe" && s.target == Some(oracle.to_string())));
+
+    server.notify_account(&Pubkey::new_unique(), &oracle, 10, &[1]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, oracle),
+        _ => panic!("expected account message"),
+    }
+
+    handle.remove_oracle(&oracle);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    server.notify_account(&Pubkey::new_unique(), &oracle, 11, &[2]);
+    server.notify_slot(SlotUpdate::Root {
+        slot: 11,
+        timestamp: 0,
+    });
+    match recv(&receiver).await {
+        Message::Slot(update) => assert_eq!(update.slot(), 11),
+        _ => panic!("expected only the slot message"),
+    }
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+}
+
+#[tokio::test]
+async fn test_stop_when_receiver_dropped() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let handle = websocket_source::start(
+        config(&server, Pubkey::new_unique()),
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    drop(receiver);
+    server.notify_slot(SlotUpdate::Root {
+        slot: 20,
+        timestamp: 0,
+    });
+    // the task ends instead of panicking or reconnecting
+    tokio::time::timeout(Duration::from_secs(5), handle.task)
+        .await
+        .expect("source stops")
+        .expect("no panic");
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+}
+
+#[tokio::test]
+async fn test_mango_data_slice() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    let data_slice = UiDataSliceConfig {
+        offset: 8,
+        length: 4,
+    };
+    websocket_source::start(
+        websocket_source::Config {
+            mango_data_slice: Some(data_slice),
+            ..config(&server, serum_program)
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    let program_config = |program: &Pubkey| {
+        subscriptions
+            .iter()
+            .find(|s| s.target == Some(program.to_string()))
+            .unwrap()
+            .config
+            .clone()
+    };
+    assert_eq!(
+        program_config(&mango_v4::id())["dataSlice"],
+        json!({"offset": 8, "length": 4})
+    );
+    assert!(program_config(&serum_program)["dataSlice"].is_null());
+
+    // the rpc node sends only the slice
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1, 2, 3, 4]);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.account.data(), &[1, 2, 3, 4]);
+            assert_eq!(update.data_slice, Some(data_slice));
+        }
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_coalesce_account_updates() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            account_coalesce_interval: Duration::from_millis(500),
+            ..config(&server, serum_program)
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    let mango_account = Pubkey::new_unique();
+    let other_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1]);
+    server.notify_program_account(&mango_v4::id(), &mango_account, 11, &[2]);
+    // older than the collected update, dropped
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[3]);
+    server.notify_program_account(&mango_v4::id(), &other_account, 9, &[4]);
+    server.notify_slot(SlotUpdate::CreatedBank {
+        slot: 12,
+        parent: 11,
+        timestamp: 0,
+    });
+
+    // slot updates are not held back
+    assert!(matches!(recv(&receiver).await, Message::Slot(_)));
+    match recv(&receiver).await {
+        Message::AccountBatch(batch) => {
+            let updates = batch
+                .iter()
+                .map(|update| (update.pubkey, update.slot, update.account.data().to_vec()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                updates,
+                vec![(other_account, 9, vec![4]), (mango_account, 11, vec![2])]
+            );
+        }
+        _ => panic!("expected account batch message"),
+    }
+}
+
+#[tokio::test]
+async fn test_get_next_slot() {
+    let slot = |update: SlotUpdate| Message::Slot(Arc::new(update));
+    let (sender, receiver) = async_channel::unbounded();
+    for message in [
+        slot(SlotUpdate::CreatedBank {
+            slot: 12,
+            parent: 11,
+            timestamp: 0,
+        }),
+        slot(SlotUpdate::OptimisticConfirmation {
+            slot: 11,
+            timestamp: 0,
+        }),
+        slot(SlotUpdate::Root {
+            slot: 10,
+            timestamp: 0,
+        }),
+    ] {
+        sender.send(message).await.unwrap();
+    }
+
+    // earlier messages of other statuses are skipped
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        websocket_source::get_next_rooted_slot(receiver.clone(), timeout)
+            .await
+            .unwrap(),
+        10
+    );
+
+    sender
+        .send(slot(SlotUpdate::OptimisticConfirmation {
+            slot: 13,
+            timestamp: 0,
+        }))
+        .await
+        .unwrap();
+    assert_eq!(
+        websocket_source::get_next_confirmed_slot(receiver.clone(), timeout)
+            .await
+            .unwrap(),
+        13
+    );
+
+    // no matching message, bails once the deadline passed
+    sender
+        .send(slot(SlotUpdate::CreatedBank {
+            slot: 14,
+            parent: 13,
+            timestamp: 0,
+        }))
+        .await
+        .unwrap();
+    let clock = MockClock::default();
+    let timeout = Duration::from_secs(30);
+    let next =
+        websocket_source::get_next_slot(receiver.clone(), timeout, SlotStatus::Rooted, &clock);
+    futures::pin_mut!(next);
+    assert!((&mut next).now_or_never().is_none());
+    assert!(receiver.is_empty());
+    clock.advance(timeout);
+    assert!(next.await.is_err());
+}
+
+#[tokio::test]
+async fn test_drop_updates_behind_watermark_after_reconnect() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    let config = websocket_source::Config {
+        commitment: CommitmentConfig::confirmed(),
+        ..config(&server, serum_program)
+    };
+    let metrics = config.metrics.clone();
+    websocket_source::start(config, vec![Pubkey::new_unique()], sender);
+
+    let no_min_context_slot =
+        |s: &Subscription| s.config.get("minContextSlot").map_or(true, Value::is_null);
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions.iter().all(no_min_context_slot));
+    // processed slots are ahead of the confirmed account writes
+    server.notify_slot(SlotUpdate::CreatedBank {
+        slot: 20,
+        parent: 19,
+        timestamp: 0,
+    });
+    recv(&receiver).await;
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 15, &[15]);
+    recv(&receiver).await;
+
+    server.disconnect_all();
+    wait_until("the reconnect", || {
+        server.subscriptions().iter().any(|s| s.connection > 1)
+    })
+    .await;
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    for subscription in subscriptions.iter() {
+        if subscription.target == Some(mango_v4::id().to_string()) {
+            assert_eq!(subscription.config["minContextSlot"], json!(15));
+        } else {
+            // nothing was forwarded from the other streams
+            assert!(no_min_context_slot(subscription), "{subscription:?}");
+        }
+    }
+    assert!(matches!(
+        recv(&receiver).await,
+        Message::Disconnected { last_slot: 20, .. }
+    ));
+
+    // the new node is behind at first, then catches up
+    for slot in [14, 17] {
+        server.notify_program_account(&mango_v4::id(), &mango_account, slot, &[slot as u8]);
+    }
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.slot, 17),
+        _ => panic!("expected account message"),
+    }
+    assert_eq!(
+        metrics
+            .accounts_behind_watermark_dropped
+            .load(Ordering::Relaxed),
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_skip_undecodable_account() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    let config = config(&server, serum_program);
+    let metrics = config.metrics.clone();
+    websocket_source::start(config, vec![Pubkey::new_unique()], sender);
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    let mango_account = Pubkey::new_unique();
+    let mut malformed = serde_json::to_value(Response {
+        context: RpcResponseContext {
+            slot: 10,
+            api_version: None,
+        },
+        value: RpcKeyedAccount {
+            pubkey: mango_account.to_string(),
+            account: ui_account(&mango_account, &mango_v4::id(), &[1]),
+        },
+    })
+    .unwrap();
+    malformed["value"]["pubkey"] = json!("not a pubkey");
+    server.notify("programSubscribe", Some(&mango_v4::id()), malformed);
+    server.notify_program_account(&mango_v4::id(), &mango_account, 11, &[2]);
+
+    // the stream stays up and the next update comes through
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.slot, 11),
+        _ => panic!("expected account message"),
+    }
+    assert_eq!(metrics.decode_errors.load(Ordering::Relaxed), 1);
+    #[allow(deprecated)]
+    let skipped = metrics.accounts_decode_skipped.load(Ordering::Relaxed);
+    assert_eq!(skipped, 1);
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+}
+
+#[tokio::test]
+async fn test_zstd_encoding() {
+    let server = MockPubsubServer::start().await;
+    let serum_program = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            encoding: UiAccountEncoding::Base64Zstd,
+            ..config(&server, serum_program)
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    for subscription in subscriptions
+        .iter()
+        .filter(|s| s.method != "slotsUpdatesSubscribe")
+    {
+        assert_eq!(
+            subscription.config["encoding"],
+            json!("base64+zstd"),
+            "{subscription:?}"
+        );
+    }
+
+    // mostly zero, like most mango accounts
+    let mango_account = Pubkey::new_unique();
+    let mut data = vec![0u8; 4096];
+    data[..4].copy_from_slice(&[1, 2, 3, 4]);
+    data[4000] = 5;
+    let mut account = AccountSharedData::new(1_000_000, data.len(), &mango_v4::id());
+    account.set_data_from_slice(&data);
+    let ui_account = UiAccount::encode(
+        &mango_account,
+        &account,
+        UiAccountEncoding::Base64Zstd,
+        None,
+        None,
+    );
+    server.notify(
+        "programSubscribe",
+        Some(&mango_v4::id()),
+        serde_json::to_value(Response {
+            context: RpcResponseContext {
+                slot: 10,
+                api_version: None,
+            },
+            value: RpcKeyedAccount {
+                pubkey: mango_account.to_string(),
+                account: ui_account,
+            },
+        })
+        .unwrap(),
+    );
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, mango_account);
+            assert_eq!(update.owner(), &mango_v4::id());
+            assert_eq!(update.account.data(), &data[..]);
+        }
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_disconnected_per_connection() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let oracles = (0..3).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    let handle = websocket_source::start(
+        websocket_source::Config {
+            // the main connection has room for one oracle, a shard takes the others
+            max_subscriptions_per_connection: Some(4),
+            ..config(&server, Pubkey::new_unique())
+        },
+        oracles,
+        sender,
+    );
+    assert_eq!(handle.connections(), 2);
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 3).await;
+
+    server.disconnect_all();
+    let mut roles = vec![];
+    while roles.len() < 2 {
+        match recv(&receiver).await {
+            Message::Disconnected { url, role, .. } => {
+                assert_eq!(url, server.url);
+                roles.push(role);
+            }
+            _ => panic!("expected disconnected message"),
+        }
+    }
+    assert!(roles.contains(&ConnectionRole::Main));
+    assert!(roles.contains(&ConnectionRole::OracleShard));
+}
+
+#[tokio::test]
+async fn test_oracle_shard_gives_up_alone() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            max_subscriptions_per_connection: Some(4),
+            max_lifetime_reconnects: Some(0),
+            ..config(&server, Pubkey::new_unique())
+        },
+        (0..3).map(|_| Pubkey::new_unique()).collect(),
+        sender,
+    );
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 3).await;
+    let main = subscriptions
+        .iter()
+        .find(|s| s.method == "programSubscribe")
+        .unwrap()
+        .connection;
+    let shard = subscriptions
+        .iter()
+        .find(|s| s.connection != main)
+        .unwrap()
+        .connection;
+
+    server.disconnect(shard);
+    match recv(&receiver).await {
+        Message::Disconnected { role, .. } => assert_eq!(role, ConnectionRole::OracleShard),
+        _ => panic!("expected disconnected message"),
+    }
+    // out of reconnects, the shard stops without closing the channel of the main connection
+    wait_until("the shard to disconnect", || {
+        server.subscriptions().iter().all(|s| s.connection == main)
+    })
+    .await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!receiver.is_closed());
+
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, mango_account),
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_max_connection_age_switches_without_gap_marker() {
+    let server = MockPubsubServer::start().await;
+    let clock = Arc::new(MockClock::default());
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            clock: clock.clone(),
+            max_connection_age: Some(Duration::from_secs(100)),
+            max_connection_age_jitter: Duration::ZERO,
+            ws_idle_timeout: Duration::from_secs(1000),
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    // the idle watchdog and the connection age
+    wait_until("2 sleeps", || clock.pending_sleeps() == 2).await;
+
+    clock.advance(Duration::from_secs(100));
+    wait_until("the switch to the next connection", || {
+        let subscriptions = server.subscriptions();
+        subscriptions.len() == SUBSCRIPTION_COUNT && subscriptions.iter().all(|s| s.connection == 2)
+    })
+    .await;
+    assert!(server.subscriptions().iter().all(|s| s.connection == 2));
+
+    // a planned switch is no disconnect, the next message is from the new connection
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, mango_account),
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_pause_resume() {
+    async fn recv_pubkey(receiver: &async_channel::Receiver<Message>) -> Pubkey {
+        match recv(receiver).await {
+            Message::Account(update) => update.pubkey,
+            _ => panic!("expected account message"),
+        }
+    }
+
+    for pause_mode in [PauseMode::Drop, PauseMode::Buffer] {
+        let server = MockPubsubServer::start().await;
+        let (sender, receiver) = async_channel::unbounded();
+        let handle = websocket_source::start(
+            websocket_source::Config {
+                pause_mode,
+                // an oracle shard next to the main connection, both are paused
+                max_subscriptions_per_connection: Some(4),
+                ..config(&server, Pubkey::new_unique())
+            },
+            (0..3).map(|_| Pubkey::new_unique()).collect(),
+            sender,
+        );
+        assert_eq!(handle.connections(), 2);
+        let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 3).await;
+        let main = subscriptions
+            .iter()
+            .find(|s| s.method == "programSubscribe")
+            .unwrap()
+            .connection;
+        let shard_oracle = subscriptions
+            .iter()
+            .find(|s| s.connection != main && s.method == "accountSubscribe")
+            .and_then(|s| s.target.as_deref())
+            .map(|target| Pubkey::from_str(target).unwrap())
+            .unwrap();
+
+        handle.pause();
+        assert!(handle.is_paused());
+        let paused_account = Pubkey::new_unique();
+        server.notify_program_account(&mango_v4::id(), &paused_account, 10, &[1]);
+        server.notify_account(&Pubkey::new_unique(), &shard_oracle, 10, &[2]);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(receiver.try_recv().is_err(), "{pause_mode:?}");
+
+        handle.resume();
+        assert!(!handle.is_paused());
+        match pause_mode {
+            PauseMode::Drop => {
+                let resumed_account = Pubkey::new_unique();
+                server.notify_program_account(&mango_v4::id(), &resumed_account, 11, &[3]);
+                assert_eq!(recv_pubkey(&receiver).await, resumed_account);
+            }
+            // both connections flush without waiting for their next message
+            PauseMode::Buffer => {
+                let mut pubkeys = vec![recv_pubkey(&receiver).await, recv_pubkey(&receiver).await];
+                pubkeys.sort();
+                let mut expected = vec![paused_account, shard_oracle];
+                expected.sort();
+                assert_eq!(pubkeys, expected);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_shutdown_sends_coalesced_updates() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let config = websocket_source::Config {
+        // never flushes by itself during the test
+        account_coalesce_interval: Duration::from_secs(3600),
+        ..config(&server, Pubkey::new_unique())
+    };
+    let metrics = config.metrics.clone();
+    let handle = websocket_source::start(config, vec![Pubkey::new_unique()], sender);
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1]);
+    wait_until("the decoded account", || {
+        metrics.accounts_decoded.load(Ordering::Relaxed) == 1
+    })
+    .await;
+    assert!(receiver.try_recv().is_err());
+
+    handle.shutdown();
+    match recv(&receiver).await {
+        Message::AccountBatch(batch) => {
+            let pubkeys = batch.iter().map(|update| update.pubkey).collect::<Vec<_>>();
+            assert_eq!(pubkeys, vec![mango_account]);
+        }
+        _ => panic!("expected account batch message"),
+    }
+    tokio::time::timeout(Duration::from_secs(5), handle.task)
+        .await
+        .expect("source stops after shutdown")
+        .unwrap();
+    assert!(receiver.recv().await.is_err());
+}
+
+#[tokio::test]
+async fn test_broadcast_lagged_subscriber() {
+    let server = MockPubsubServer::start().await;
+    let config = config(&server, Pubkey::new_unique());
+    let metrics = config.metrics.clone();
+    let (_handle, broadcast) =
+        websocket_source::start_broadcast(config, vec![Pubkey::new_unique()], 2);
+    let mut fast = broadcast.subscribe();
+    let mut slow = broadcast.subscribe();
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    let accounts = (0..5).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+    for (slot, account) in accounts.iter().enumerate() {
+        server.notify_program_account(&mango_v4::id(), account, slot as u64, &[1]);
+        let message = tokio::time::timeout(Duration::from_secs(5), fast.recv())
+            .await
+            .expect("message within timeout");
+        match message {
+            Some(Message::Account(update)) => assert_eq!(&update.pubkey, account),
+            _ => panic!("expected account message"),
+        }
+    }
+    assert_eq!(metrics.broadcast_lagged.load(Ordering::Relaxed), 0);
+
+    // only the newest two fit the capacity, the slow subscriber skips ahead
+    for account in &accounts[3..] {
+        match slow.recv().await {
+            Some(Message::Account(update)) => assert_eq!(&update.pubkey, account),
+            _ => panic!("expected account message"),
+        }
+    }
+    assert_eq!(metrics.broadcast_lagged.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn test_processed_stall_resubscribes_at_confirmed() {
+    // a rejected confirmed subscription keeps the processed one
+    for reject_confirmed in [false, true] {
+        let server = MockPubsubServer::start().await;
+        if reject_confirmed {
+            server.reject(1, |s| s.config["commitment"] == json!("confirmed"));
+        }
+        let clock = Arc::new(MockClock::default());
+        let (sender, receiver) = async_channel::unbounded();
+        let (events_sender, events) = async_channel::unbounded();
+        let config = websocket_source::Config {
+            clock: clock.clone(),
+            commitment: CommitmentConfig::processed(),
+            processed_stall_timeout: Some(Duration::from_secs(30)),
+            events: Some(events_sender),
+            ..config(&server, Pubkey::new_unique())
+        };
+        let metrics = config.metrics.clone();
+        websocket_source::start(config, vec![Pubkey::new_unique()], sender);
+        let mango_commitments = || {
+            server
+                .subscriptions()
+                .into_iter()
+                .filter(|s| s.target == Some(mango_v4::id().to_string()))
+                .map(|s| s.config["commitment"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+        assert_eq!(mango_commitments(), vec!["processed"]);
+
+        // slots advance, but no mango accounts arrive
+        server.notify_slot(SlotUpdate::CreatedBank {
+            slot: 10,
+            parent: 9,
+            timestamp: 0,
+        });
+        recv(&receiver).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mango_commitments(), vec!["processed"]);
+
+        clock.advance(Duration::from_secs(31));
+        server.notify_slot(SlotUpdate::CreatedBank {
+            slot: 11,
+            parent: 10,
+            timestamp: 0,
+        });
+        recv(&receiver).await;
+        if reject_confirmed {
+            wait_until("the rejected subscription", || {
+                server.rejections_pending() == 0
+            })
+            .await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(mango_commitments(), vec!["processed"]);
+        } else {
+            wait_until("the confirmed subscription", || {
+                mango_commitments().contains(&"confirmed".to_string())
+            })
+            .await;
+        }
+
+        let mango_account = Pubkey::new_unique();
+        server.notify_program_account(&mango_v4::id(), &mango_account, 12, &[1]);
+        match recv(&receiver).await {
+            Message::Account(update) => assert_eq!(update.pubkey, mango_account),
+            _ => panic!("expected account message"),
+        }
+        let fallbacks = u64::from(!reject_confirmed);
+        wait_until("the fallback metric", || {
+            metrics.processed_stall_fallbacks.load(Ordering::Relaxed) == fallbacks
+        })
+        .await;
+        let fallback_events = std::iter::from_fn(|| events.try_recv().ok())
+            .filter(|event| matches!(event, SourceEvent::ProcessedStallFallback { .. }))
+            .count();
+        assert_eq!(fallback_events, fallbacks as usize);
+        // on the same connection
+        assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+    }
+}
+
+#[tokio::test]
+async fn test_retry_rejected_oracle_subscription() {
+    let server = MockPubsubServer::start().await;
+    let clock = Arc::new(MockClock::default());
+    let rejected_oracle = Pubkey::new_unique();
+    let oracle = Pubkey::new_unique();
+    server.reject(1, move |s| s.target == Some(rejected_oracle.to_string()));
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            clock: clock.clone(),
+            oracle_retry_interval: Duration::from_secs(10),
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![rejected_oracle, oracle],
+        sender,
+    );
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    assert!(subscriptions
+        .iter()
+        .all(|s| s.target != Some(rejected_oracle.to_string())));
+
+    // the other oracle is not held back by the failed one
+    let oracle_owner = Pubkey::new_unique();
+    server.notify_account(&oracle_owner, &oracle, 10, &[1]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, oracle),
+        _ => panic!("expected account message"),
+    }
+
+    // the idle watchdog and the oracle retry
+    wait_until("2 sleeps", || clock.pending_sleeps() == 2).await;
+
+    clock.advance(Duration::from_secs(10));
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 1).await;
+    server.notify_account(&oracle_owner, &rejected_oracle, 11, &[2]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, rejected_oracle),
+        _ => panic!("expected account message"),
+    }
+    assert!(server.subscriptions().iter().all(|s| s.connection == 1));
+}
+
+#[tokio::test]
+async fn test_encoding_preference_fallback() {
+    let server = MockPubsubServer::start().await;
+    server.reject(1, |s| {
+        s.target == Some(mango_v4::id().to_string()) && s.config["encoding"] == json!("base64+zstd")
+    });
+    let (sender, receiver) = async_channel::unbounded();
+    websocket_source::start(
+        websocket_source::Config {
+            encoding_preference: vec![UiAccountEncoding::Base64Zstd, UiAccountEncoding::Base64],
+            ..config(&server, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+
+    // resubscribed with the next encoding, on the same connection
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    let mango = subscriptions
+        .iter()
+        .find(|s| s.target == Some(mango_v4::id().to_string()))
+        .expect("mango program subscription");
+    assert_eq!(mango.config["encoding"], json!("base64"));
+    assert_eq!(mango.connection, 1);
+
+    let mango_account = Pubkey::new_unique();
+    server.notify_program_account(&mango_v4::id(), &mango_account, 10, &[1, 2]);
+    match recv(&receiver).await {
+        Message::Account(update) => {
+            assert_eq!(update.pubkey, mango_account);
+            assert_eq!(update.account.data(), &[1, 2]);
+        }
+        _ => panic!("expected account message"),
+    }
+}
+
+#[tokio::test]
+async fn test_watch_signature() {
+    let server = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let handle = websocket_source::start(
+        config(&server, Pubkey::new_unique()),
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    let signature = Signature::new_unique();
+    handle.watch_signature(signature);
+    let subscriptions = server.wait_for_subscriptions(SUBSCRIPTION_COUNT + 1).await;
+    let subscription = subscriptions
+        .iter()
+        .find(|s| s.method == "signatureSubscribe")
+        .expect("signature subscription");
+    assert_eq!(subscription.target, Some(signature.to_string()));
+    assert_eq!(subscription.config["commitment"], json!("confirmed"));
+
+    server.notify_signature(
+        &signature,
+        12,
+        Some(TransactionError::InsufficientFundsForFee),
+    );
+    match recv(&receiver).await {
+        Message::Signature {
+            signature: reported,
+            slot,
+            err,
+        } => {
+            assert_eq!(reported, signature);
+            assert_eq!(slot, 12);
+            assert_eq!(err, Some(TransactionError::InsufficientFundsForFee));
+        }
+        _ => panic!("expected signature message"),
+    }
+}
+
+#[tokio::test]
+async fn test_dual_source_disagreement() {
+    let primary = MockPubsubServer::start().await;
+    let secondary = MockPubsubServer::start().await;
+    let (sender, receiver) = async_channel::unbounded();
+    let handle = websocket_source::start(
+        websocket_source::Config {
+            dual_source_ws_url: Some(secondary.url.clone()),
+            ..config(&primary, Pubkey::new_unique())
+        },
+        vec![Pubkey::new_unique()],
+        sender,
+    );
+    assert_eq!(handle.connections(), 2);
+    primary.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    secondary.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    // the first update is forwarded, the same data from the other node is not
+    let agreed = Pubkey::new_unique();
+    primary.notify_program_account(&mango_v4::id(), &agreed, 10, &[1]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, agreed),
+        _ => panic!("expected account message"),
+    }
+    secondary.notify_program_account(&mango_v4::id(), &agreed, 10, &[1]);
+
+    let disputed = Pubkey::new_unique();
+    primary.notify_program_account(&mango_v4::id(), &disputed, 11, &[2]);
+    match recv(&receiver).await {
+        Message::Account(update) => assert_eq!(update.pubkey, disputed),
+        _ => panic!("expected account message"),
+    }
+    secondary.notify_program_account(&mango_v4::id(), &disputed, 11, &[3]);
+    match recv(&receiver).await {
+        Message::Disagreement { pubkey, slot } => {
+            assert_eq!(pubkey, disputed);
+            assert_eq!(slot, 11);
+        }
+        _ => panic!("expected disagreement message"),
+    }
+
+    // slot updates are forwarded once
+    let created_bank = |slot: u64| SlotUpdate::CreatedBank {
+        slot,
+        parent: slot - 1,
+        timestamp: 0,
+    };
+    primary.notify_slot(created_bank(20));
+    match recv(&receiver).await {
+        Message::Slot(update) => assert_eq!(update.slot(), 20),
+        _ => panic!("expected slot message"),
+    }
+    secondary.notify_slot(created_bank(20));
+    primary.notify_slot(created_bank(21));
+    match recv(&receiver).await {
+        Message::Slot(update) => assert_eq!(update.slot(), 21),
+        _ => panic!("expected slot message"),
+    }
+
+    // no gap while the other node still streams, then one marker with the newest slot of both
+    primary.disconnect_all();
+    wait_until("the primary reconnect", || {
+        primary.subscriptions().iter().any(|s| s.connection > 1)
+    })
+    .await;
+    secondary.disconnect_all();
+    match recv(&receiver).await {
+        Message::Disconnected { last_slot, .. } => assert_eq!(last_slot, 21),
+        _ => panic!("expected disconnected message"),
+    }
+    secondary.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(receiver.try_recv().is_err());
+}
+
+fn stub_oracle_data(price: f64) -> Vec<u8> {
+    let mut oracle: StubOracle = bytemuck::Zeroable::zeroed();
+    oracle.price = I80F48::from_num(price);
+    let mut data = StubOracle::discriminator().to_vec();
+    data.extend_from_slice(bytemuck::bytes_of(&oracle));
+    data
+}
+
+#[tokio::test]
+async fn test_oracle_min_price_change() {
+    let server = MockPubsubServer::start().await;
+    let oracle = Pubkey::new_unique();
+    let (sender, receiver) = async_channel::unbounded();
+    let config = websocket_source::Config {
+        oracle_min_price_change: Some(0.01),
+        oracle_max_skipped_slots: 10,
+        ..config(&server, Pubkey::new_unique())
+    };
+    let metrics = config.metrics.clone();
+    websocket_source::start(config, vec![oracle], sender);
+    server.wait_for_subscriptions(SUBSCRIPTION_COUNT).await;
+
+    for (slot, price) in [
+        (10, 100.0),
+        // moved by 0.5%, suppressed
+        (11, 100.5),
+        (12, 102.0),
+        // unchanged, but oracle_max_skipped_slots after the last forwarded one
+        (22, 102.0),
+    ] {
+        server.notify_account(&mango_v4::id(), &oracle, slot, &stub_oracle_data(price));
+    }
+    let mut slots = vec![];
+    for _ in 0..3 {
+        match recv(&receiver).await {
+            Message::Account(update) => slots.push(update.slot),
+            _ => panic!("expected account message"),
+        }
+    }
+    assert_eq!(slots, vec![10, 12, 22]);
+    assert_eq!(metrics.oracle_updates_forwarded.load(Ordering::Relaxed), 3);
+    assert_eq!(metrics.oracle_updates_suppressed.load(Ordering::Relaxed), 1);
+}